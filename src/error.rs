@@ -1,17 +1,127 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
 
 #[derive(Debug)]
 pub enum RosettaError {
-    UnexpectedFormat,
+    MissingLangHeader {
+        task: String,
+    },
+    MissingLangBody {
+        task: String,
+        lang: String,
+    },
+    MalformedLangBlock {
+        task: String,
+        lang: String,
+    },
+    MissingPage {
+        title: String,
+    },
+    MissingRevision {
+        title: String,
+    },
+    ContinuationMalformed {
+        args: Vec<(String, String)>,
+        continuation: String,
+    },
+    ApiError {
+        code: String,
+        info: String,
+        args: Vec<(String, String)>,
+    },
+    MissingFixture {
+        query: String,
+    },
+    FileFailed {
+        path: String,
+        source: io::Error,
+    },
+    GitFailed {
+        command: String,
+        message: String,
+    },
+    Drift {
+        stale: usize,
+        missing: usize,
+        extra: usize,
+    },
+    UnsupportedState {
+        path: String,
+        version: u32,
+    },
+}
+
+impl RosettaError {
+    // for use with map_err on file operations, to keep track of the path
+    pub fn file(path: &str) -> impl FnOnce(io::Error) -> RosettaError + '_ {
+        move |source| RosettaError::FileFailed {
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    // for use with map_err on repository operations
+    pub fn git(command: &str) -> impl FnOnce(git2::Error) -> RosettaError + '_ {
+        move |e| RosettaError::GitFailed {
+            command: command.to_owned(),
+            message: e.message().to_owned(),
+        }
+    }
 }
 
 impl fmt::Display for RosettaError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RosettaError::UnexpectedFormat => write!(f, "Unknown format."),
+            RosettaError::MissingLangHeader { task } => {
+                write!(f, "{}: language header without a language", task)
+            }
+            RosettaError::MissingLangBody { task, lang } => {
+                write!(f, "{}: no body for language section {}", task, lang)
+            }
+            RosettaError::MalformedLangBlock { task, lang } => {
+                write!(f, "{}: malformed <lang> block in section {}", task, lang)
+            }
+            RosettaError::MissingPage { title } => write!(f, "{}: page not in response", title),
+            RosettaError::MissingRevision { title } => {
+                write!(f, "{}: no revision in response", title)
+            }
+            RosettaError::ContinuationMalformed { args, continuation } => write!(
+                f,
+                "Malformed continuation {} for query {:?}",
+                continuation, args
+            ),
+            RosettaError::ApiError { code, info, args } => {
+                write!(f, "API error {}: {} for query {:?}", code, info, args)
+            }
+            RosettaError::MissingFixture { query } => {
+                write!(f, "No recorded response for: {}", query)
+            }
+            RosettaError::FileFailed { path, source } => write!(f, "{}: {}", path, source),
+            RosettaError::GitFailed { command, message } => write!(f, "{}: {}", command, message),
+            RosettaError::Drift {
+                stale,
+                missing,
+                extra,
+            } => write!(
+                f,
+                "Mirror differs from the wiki: {} stale, {} missing, {} extra tasks",
+                stale, missing, extra
+            ),
+            RosettaError::UnsupportedState { path, version } => write!(
+                f,
+                "{}: state version {} is newer than this version of the mirror",
+                path, version
+            ),
         }
     }
 }
 
-impl Error for RosettaError {}
+impl Error for RosettaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RosettaError::FileFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
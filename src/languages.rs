@@ -1,7 +1,9 @@
 use crate::Languages;
+use log::warn;
 use onig::Regex;
 use std::collections::*;
 use std::error::*;
+use std::sync::Mutex;
 
 #[derive(Debug)]
 struct LangExtension(String, String);
@@ -15,6 +17,7 @@ impl LangExtension {
 pub struct Langs {
     names: HashMap<String, String>, // map from lowercase name to language name of choice
     extensions: HashMap<String, String>, // map from lowercase name to language extension
+    unknown: Mutex<BTreeSet<String>>, // names looked up but not found
 }
 
 impl Langs {
@@ -231,19 +234,24 @@ impl Langs {
         Ok(Langs {
             names: name_map,
             extensions: extensions_map,
+            unknown: Mutex::new(BTreeSet::new()),
         })
     }
 
-    pub fn lookup(self: &Self, name: String) -> String {
+    pub fn lookup(&self, name: String) -> String {
         if let Some(found_name) = self.names.get(&name.to_lowercase()) {
             found_name.clone()
         } else {
-            println!("LANGUAGE NOT FOUND: {}", name);
+            warn!(lang = name.as_str(); "language not found");
+            self.unknown
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(name.clone());
             name
         }
     }
 
-    pub fn lookup_extension(self: &Self, name: String) -> String {
+    pub fn lookup_extension(&self, name: String) -> String {
         let lc_name = name.to_lowercase();
         if let Some(found_name) = self.extensions.get(&lc_name) {
             found_name.clone()
@@ -251,4 +259,13 @@ impl Langs {
             lc_name
         }
     }
+
+    pub fn unknown_languages(&self) -> Vec<String> {
+        self.unknown
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
 }
@@ -2,24 +2,36 @@
 extern crate serde_derive;
 
 use crate::error::RosettaError;
+use crate::repo::{Author, Repo};
+use crate::report::Reporter;
+use crate::site::SiteProfile;
+use crate::state::{change_position, read_json_file, MirrorState, SyncCursor, WrittenTask};
+use crate::transport::MediaWikiTransport;
+use log::{debug, error, info, warn};
 use serde::Deserialize;
 use serde_json::Value;
 use std::collections::HashSet;
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::process::Command;
-use std::str;
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
 
-mod error;
+pub mod error;
 mod languages;
+pub mod logging;
+mod repo;
+pub mod report;
+pub mod site;
+mod state;
+pub mod transport;
 mod write_code_onig;
 
 pub trait ContinuedQuery {
-    fn concat(self: &mut Self, other: Self);
+    fn concat(&mut self, other: Self);
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 struct Task {
     pageid: u64,
     title: String,
@@ -39,12 +51,48 @@ impl ContinuedQuery for Tasks {
 #[derive(Deserialize, Debug)]
 struct PageDetail {
     pageid: u64,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    missing: bool,
+    #[serde(default)]
+    lastrevid: u64,
+    // absent for missing pages and for pages whose content didn't fit
+    // into this part of a continued query
+    #[serde(default)]
+    revisions: Vec<RevisionDetail>,
+    // only those asked for with clcategories
+    #[serde(default)]
+    categories: Vec<PageCategory>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PageCategory {
     title: String,
 }
 
+#[derive(Deserialize, Debug, Default)]
+struct Pages {
+    pages: Vec<PageDetail>,
+}
+
+impl ContinuedQuery for Pages {
+    fn concat(&mut self, other: Self) {
+        for page in other.pages {
+            match self.pages.iter_mut().find(|p| p.pageid == page.pageid) {
+                Some(existing) => {
+                    existing.revisions.extend(page.revisions);
+                    existing.categories.extend(page.categories);
+                }
+                None => self.pages.push(page),
+            }
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
-struct RevisionDetail<'a> {
-    content: &'a str,
+struct RevisionDetail {
+    content: String,
     revid: u64,
     timestamp: String,
     user: String,
@@ -67,14 +115,41 @@ impl ContinuedQuery for Languages {
     }
 }
 
+// A recent change: an edit, a page creation or a log event. Log events
+// carry no revision ids, and not every query asks for every field.
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
 pub struct Revision {
+    #[serde(rename = "type")]
+    kind: String,
     pageid: u64,
     old_revid: u64,
     rcid: u64,
     revid: u64,
     timestamp: String,
     title: String,
+    user: String,
+    comment: String,
+    logtype: Option<String>,
+    logaction: Option<String>,
+    logparams: Value,
+}
+
+impl Revision {
+    fn is_log(&self, logtype: &str, logaction: &str) -> bool {
+        self.kind == "log"
+            && self.logtype.as_deref() == Some(logtype)
+            && self.logaction.as_deref() == Some(logaction)
+    }
+
+    // new title of a page move, the old one being the title of the event
+    fn move_target(&self) -> Option<&str> {
+        if self.is_log("move", "move") || self.is_log("move", "move_redir") {
+            self.logparams["target_title"].as_str()
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
@@ -83,33 +158,34 @@ pub struct Revisions {
 }
 
 impl ContinuedQuery for Revisions {
-    fn concat(self: &mut Self, other: Self) {
+    fn concat(&mut self, other: Self) {
         self.recentchanges.extend(other.recentchanges)
     }
 }
 
-#[derive(Clone, Hash, Eq, PartialEq, Serialize, Deserialize, Debug)]
-struct WrittenTask {
-    pageid: u64,
-    revid: u64,
+#[derive(Debug)]
+pub struct SyncOptions {
+    // refetch every task in every category, ignoring tallies and timestamp
+    pub full_resync: bool,
+    // number of task batches downloaded at the same time
+    pub jobs: usize,
+    // only compare the tallies with the wiki, changing nothing
+    pub verify: bool,
+    // mirror new categories one revision at a time, from each task's first
+    pub backfill: bool,
 }
 
-impl WrittenTask {
-    fn new(pageid: u64, revid: u64) -> Self {
-        Self { pageid, revid }
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            full_resync: false,
+            jobs: 1,
+            verify: false,
+            backfill: false,
+        }
     }
 }
 
-fn query_api(args: Vec<(String, String)>) -> Result<String, Box<dyn Error>> {
-    let mut query = url::Url::parse("http://rosettacode.org/mw/api.php")?;
-
-    query.query_pairs_mut().extend_pairs(args.into_iter());
-    let mut response = (reqwest::blocking::get(query.as_str()))?;
-    let mut body = String::new();
-    response.read_to_string(&mut body)?;
-    Ok(body)
-}
-
 fn to_string_pair(s: &(&str, &str)) -> (String, String) {
     (s.0.to_string(), s.1.to_string())
 }
@@ -136,12 +212,13 @@ fn make_recentchanges_query_args(rcstart: Option<String>) -> Vec<(String, String
         ]
     });
 
-    let mut args = vec![
+    let mut args = [
         ("action", "query"),
         ("format", "json"),
         ("formatversion", "2"),
         ("list", "recentchanges"),
-        ("rcprop", "title|ids|timestamp"),
+        ("rcprop", "title|ids|timestamp|user|comment|loginfo"),
+        ("rctype", "edit|new|log"),
         ("rclimit", "500"),
     ]
     .iter()
@@ -152,15 +229,86 @@ fn make_recentchanges_query_args(rcstart: Option<String>) -> Vec<(String, String
     args
 }
 
-fn make_task_query_args(task: &Task) -> Vec<(String, String)> {
+fn make_latest_change_query_args() -> Vec<(String, String)> {
+    [
+        ("action", "query"),
+        ("format", "json"),
+        ("formatversion", "2"),
+        ("list", "recentchanges"),
+        ("rcprop", "timestamp|ids"),
+        ("rclimit", "1"),
+    ]
+    .iter()
+    .map(to_string_pair)
+    .collect()
+}
+
+// the oldest change the wiki still keeps, which bounds how far back
+// recent changes can be followed
+fn make_oldest_change_query_args() -> Vec<(String, String)> {
+    [
+        ("action", "query"),
+        ("format", "json"),
+        ("formatversion", "2"),
+        ("list", "recentchanges"),
+        ("rcprop", "timestamp"),
+        ("rcdir", "newer"),
+        ("rclimit", "1"),
+    ]
+    .iter()
+    .map(to_string_pair)
+    .collect()
+}
+
+// the most pageids the API accepts in one request for revision content
+const TASK_BATCH_SIZE: usize = 50;
+
+fn join_pageids(pageids: impl Iterator<Item = u64>) -> String {
+    pageids
+        .map(|pageid| pageid.to_string())
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn make_info_query_args(tasks: &[WrittenTask]) -> Vec<(String, String)> {
+    let pageids = join_pageids(tasks.iter().map(|task| task.pageid));
+    [
+        ("action", "query"),
+        ("format", "json"),
+        ("formatversion", "2"),
+        ("prop", "info"),
+        ("pageids", &pageids),
+    ]
+    .iter()
+    .map(to_string_pair)
+    .collect()
+}
+
+fn make_task_query_args(tasks: &[Task]) -> Vec<(String, String)> {
+    let pageids = join_pageids(tasks.iter().map(|task| task.pageid));
     [
         ("action", "query"),
         ("format", "json"),
         ("formatversion", "2"),
         ("prop", "revisions"),
         ("rvprop", "content|ids|timestamp|user|comment"),
-        ("pageids", &task.pageid.to_string()),
-        ("continue", ""),
+        ("pageids", &pageids),
+    ]
+    .iter()
+    .map(to_string_pair)
+    .collect()
+}
+
+fn make_category_check_query_args(tasks: &[Task], cname: &str) -> Vec<(String, String)> {
+    let pageids = join_pageids(tasks.iter().map(|task| task.pageid));
+    [
+        ("action", "query"),
+        ("format", "json"),
+        ("formatversion", "2"),
+        ("prop", "categories"),
+        ("cllimit", "max"),
+        ("clcategories", &("Category:".to_owned() + cname)),
+        ("pageids", &pageids),
     ]
     .iter()
     .map(to_string_pair)
@@ -184,263 +332,1407 @@ fn make_revision_query_args(revision: &Revision) -> Vec<(String, String)> {
     .collect()
 }
 
-fn to_continue_pair(ca: (&String, &Value)) -> Result<(String, String), Box<dyn Error>> {
-    let cp1 = ca.1.as_str().ok_or(RosettaError::UnexpectedFormat)?;
-    Ok((ca.0.clone(), cp1.to_owned()))
+// every revision of a page, oldest first; content limits how many fit
+// into each part of the response
+fn make_history_query_args(task: &Task) -> Vec<(String, String)> {
+    [
+        ("action", "query"),
+        ("format", "json"),
+        ("formatversion", "2"),
+        ("prop", "revisions"),
+        ("rvprop", "content|ids|timestamp|user|comment"),
+        ("pageids", &task.pageid.to_string()),
+        ("rvdir", "newer"),
+        ("rvlimit", "50"),
+    ]
+    .iter()
+    .map(to_string_pair)
+    .collect()
+}
+
+fn to_continue_pair(ca: (&String, &Value)) -> Option<(String, String)> {
+    let cp1 = ca.1.as_str()?;
+    Some((ca.0.clone(), cp1.to_owned()))
+}
+
+// Parse an API response, turning an `error` block into a failure and
+// reporting any `warnings`.
+fn parse_response(response: &str, args: &[(String, String)]) -> Result<Value, Box<dyn Error>> {
+    let v: Value = serde_json::from_str(response)?;
+
+    if let Some(warnings) = v["warnings"].as_object() {
+        for (module, warning) in warnings {
+            let text = warning["warnings"]
+                .as_str()
+                .or_else(|| warning["*"].as_str())
+                .map_or_else(|| warning.to_string(), |text| text.to_owned());
+            warn!(module = module.as_str(); "API warning: {}", text);
+        }
+    }
+
+    let error = &v["error"];
+    if error.is_object() {
+        return Err(Box::new(RosettaError::ApiError {
+            code: error["code"].as_str().unwrap_or_default().to_owned(),
+            info: error["info"].as_str().unwrap_or_default().to_owned(),
+            args: args.to_vec(),
+        }));
+    }
+
+    Ok(v)
 }
 
 fn query<'a, T: Deserialize<'a> + Default + ContinuedQuery>(
+    transport: &dyn MediaWikiTransport,
     query_args: Vec<(String, String)>,
 ) -> Result<T, Box<dyn Error>> {
     let mut complete: T = Default::default();
+    query_parts(transport, query_args, |partial| {
+        complete.concat(partial);
+        Ok(())
+    })?;
+    Ok(complete)
+}
 
+// Hand each part of a continued query to `f` as it arrives, for results
+// too large to collect in memory.
+fn query_parts<'a, T: Deserialize<'a>>(
+    transport: &dyn MediaWikiTransport,
+    query_args: Vec<(String, String)>,
+    mut f: impl FnMut(T) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
     let mut cont_args = vec![("continue".to_owned(), "".to_owned())];
 
     loop {
         let mut ac = query_args.clone();
         ac.extend(cont_args);
-        let s = query_api(ac)?;
-        let v: Value = serde_json::from_str(&s)?;
+        let s = transport.get(&ac)?;
+        let v = parse_response(&s, &ac)?;
         let qv = &v["query"];
         let partial = T::deserialize(qv.clone())?; // why the clone?
-        complete.concat(partial);
+        f(partial)?;
 
         let cv = &v["continue"];
 
         if !cv.is_object() {
-            return Ok(complete);
+            return Ok(());
         }
 
         cont_args = cv
             .as_object()
-            .ok_or(RosettaError::UnexpectedFormat)?
-            .iter()
-            .map(to_continue_pair)
-            .collect::<Result<_, _>>()?;
+            .and_then(|co| co.iter().map(to_continue_pair).collect())
+            .ok_or_else(|| RosettaError::ContinuationMalformed {
+                args: query_args.clone(),
+                continuation: cv.to_string(),
+            })?;
     }
 }
 
+// Everything the steps of a sync share: where pages come from, how they
+// are turned into files, and where the outcome is accounted for.
+struct Mirror<'a> {
+    transport: &'a dyn MediaWikiTransport,
+    site: &'a SiteProfile,
+    lan: &'a languages::Langs,
+    options: &'a SyncOptions,
+    report: &'a Reporter,
+    repo: &'a Repo,
+    // read once, and kept up to date in memory through the run
+    state: Mutex<MirrorState>,
+}
+
+impl Mirror<'_> {
+    fn state(&self) -> MutexGuard<'_, MirrorState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// written task along with the timestamp, user, comment and title of its revision
+type WrittenRevision = (WrittenTask, String, String, String, String);
+
 fn write_task_response(
-    lan: &languages::Langs,
+    mirror: &Mirror,
     directory: &str,
-    response: &str,
-) -> Result<(WrittenTask, String, String, String, String), Box<dyn Error>> {
-    let v: &Value = &serde_json::from_str(response)?;
-    let p0 = &v["query"]["pages"][0];
-
-    let pd = PageDetail::deserialize(p0)?;
-    let rd = RevisionDetail::deserialize(&p0["revisions"][0])?;
+    page: PageDetail,
+) -> Result<WrittenRevision, Box<dyn Error>> {
+    let title = page.title;
+    let rd = page
+        .revisions
+        .into_iter()
+        .next()
+        .ok_or_else(|| RosettaError::MissingRevision {
+            title: title.clone(),
+        })?;
 
-    write_code_onig::write_code(lan, directory, &pd.title, rd.content)?;
+    let files = write_code_onig::write_code(
+        mirror.lan,
+        &mirror.site.header_template,
+        directory,
+        &title,
+        &rd.content,
+    )?;
+    mirror.report.files_written(&files);
     Ok((
-        WrittenTask::new(pd.pageid, rd.revid),
+        WrittenTask::new(directory, page.pageid, &title, rd.revid, files)?,
         rd.timestamp,
         rd.user,
         rd.comment,
-        pd.title,
+        title,
     ))
 }
 
 fn write_revision(
-    lan: &languages::Langs,
+    mirror: &Mirror,
     directory: &str,
     revision: &Revision,
-) -> Result<(WrittenTask, String, String, String, String), Box<dyn Error>> {
-    let response = &query_api(make_revision_query_args(revision))?;
-    write_task_response(lan, directory, response)
+) -> Result<WrittenRevision, Box<dyn Error>> {
+    let args = make_revision_query_args(revision);
+    let response = &mirror.transport.get(&args)?;
+    let v = parse_response(response, &args)?;
+    let page = Pages::deserialize(&v["query"])?
+        .pages
+        .into_iter()
+        .next()
+        .ok_or_else(|| RosettaError::MissingPage {
+            title: revision.title.clone(),
+        })?;
+    write_task_response(mirror, directory, page)
 }
 
-fn write_task(
-    lan: &languages::Langs,
+// Tasks that were written, along with those that could not be fetched
+// or written and should be tried again on the next run.
+type BatchResult = (Vec<WrittenTask>, Vec<Task>);
+
+fn write_task_batch(mirror: &Mirror, directory: &str, batch: &[Task]) -> BatchResult {
+    debug!(category = directory, tasks = batch.len(); "fetching batch");
+    let mut pages = match query::<Pages>(mirror.transport, make_task_query_args(batch)) {
+        Ok(pages) => pages.pages,
+        Err(e) => {
+            error!(category = directory, tasks = batch.len(); "fetch failed: {}", e);
+            for task in batch {
+                mirror.report.fetch_failed(directory, &task.title);
+            }
+            return (vec![], batch.to_vec());
+        }
+    };
+
+    let mut written = vec![];
+    let mut failed = vec![];
+    for task in batch {
+        let result = match pages.iter().position(|page| page.pageid == task.pageid) {
+            // deleted since the category was listed, nothing to retry
+            Some(i) if pages[i].missing => continue,
+            Some(i) => write_task_response(mirror, directory, pages.swap_remove(i)),
+            None => Err(RosettaError::MissingPage {
+                title: task.title.clone(),
+            }
+            .into()),
+        };
+        match result {
+            Ok((wt, _, _, _, _)) => written.push(wt),
+            Err(e) => {
+                error!(category = directory, task = task.title.as_str(); "task failed: {}", e);
+                mirror.report.fetch_failed(directory, &task.title);
+                failed.push(task.clone());
+            }
+        }
+    }
+    (written, failed)
+}
+
+// Report each written task as added, or as updated if the tally already
+// had it.
+fn report_written_tasks(
+    mirror: &Mirror,
     directory: &str,
-    task: &Task,
-) -> Result<WrittenTask, Box<dyn Error>> {
-    let response = &query_api(make_task_query_args(task))?;
-    let (wt, _, _, _, _) = write_task_response(lan, directory, response)?;
-    Ok(wt)
+    tally: &HashSet<WrittenTask>,
+    written: &HashSet<WrittenTask>,
+) {
+    let mut written = written.iter().collect::<Vec<_>>();
+    written.sort_by_key(|task| task.pageid);
+    for task in written {
+        if tally.iter().any(|t| t.pageid == task.pageid) {
+            mirror.report.task_updated(directory, &task.title);
+        } else {
+            mirror.report.task_added(directory, &task.title);
+        }
+    }
 }
 
-fn write_tasks(tasks: &Tasks, lan: &languages::Langs, directory: &str) -> HashSet<WrittenTask> {
-    // flat_map trick ref : https://stackoverflow.com/a/28572170/509928
-    tasks
-        .categorymembers
+fn write_tasks(
+    mirror: &Mirror,
+    tasks: &[Task],
+    directory: &str,
+) -> (HashSet<WrittenTask>, Vec<Task>) {
+    let batches = Mutex::new(tasks.chunks(TASK_BATCH_SIZE));
+    let next_batch = || {
+        batches
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .next()
+    };
+
+    thread::scope(|scope| {
+        let workers = (0..mirror.options.jobs.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut written = Vec::new();
+                    let mut failed = Vec::new();
+                    while let Some(batch) = next_batch() {
+                        let (w, f) = write_task_batch(mirror, directory, batch);
+                        written.extend(w);
+                        failed.extend(f);
+                    }
+                    (written, failed)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut written = HashSet::new();
+        let mut failed = Vec::new();
+        for worker in workers {
+            let (w, f) = worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            written.extend(w);
+            failed.extend(f);
+        }
+        (written, failed)
+    })
+}
+
+// Where older mirrors kept what is now in the state file: tally and
+// failed tasks in each category's directory, and the timestamp of the
+// last change committed at the root.
+fn legacy_tally_path(directory: &str) -> String {
+    directory.to_string() + "/tasks"
+}
+
+fn legacy_failed_path(directory: &str) -> String {
+    directory.to_string() + "/failed"
+}
+
+const LEGACY_TIMESTAMP_FILE: &str = "revision_timestamp";
+
+fn legacy_paths(site: &SiteProfile) -> Vec<String> {
+    let mut paths = vec![LEGACY_TIMESTAMP_FILE.to_owned()];
+    for category in &site.task_categories {
+        paths.push(legacy_tally_path(category));
+        paths.push(legacy_failed_path(category));
+    }
+    paths
+}
+
+// The state file, with whatever an older mirror kept elsewhere folded
+// in until migrate_state removes them.
+fn read_state(site: &SiteProfile) -> Result<MirrorState, Box<dyn Error>> {
+    let mut state = match MirrorState::load()? {
+        Some(state) => state,
+        None => {
+            let mut state = MirrorState::default();
+            if Path::new(LEGACY_TIMESTAMP_FILE).exists() {
+                let timestamp: String = read_json_file(LEGACY_TIMESTAMP_FILE)?;
+                state.cursor = Some(SyncCursor::new(&timestamp, 0));
+            }
+            for category in &site.task_categories {
+                let path = legacy_tally_path(category);
+                if Path::new(&path).exists() {
+                    let tasks: HashSet<WrittenTask> = read_json_file(&path)?;
+                    state.set_tasks(category, &tasks);
+                }
+            }
+            state
+        }
+    };
+    for category in &site.task_categories {
+        let path = legacy_failed_path(category);
+        if Path::new(&path).exists() {
+            let failed: Vec<Task> = read_json_file(&path)?;
+            state.set_failed(category, &failed);
+        }
+    }
+    Ok(state)
+}
+
+// Write the state as it stands. This happens with every commit, so that
+// the state matches the history, and once more at the end of the run.
+fn store_state(mirror: &Mirror) -> Result<(), Box<dyn Error>> {
+    mirror.repo.open()?;
+    mirror.state().save()
+}
+
+// Move the state of an older mirror into the state file, filling in
+// what its tallies didn't keep: each task's title from the wiki, and
+// the programs found in its directory.
+fn migrate_state(mirror: &Mirror) -> Result<(), Box<dyn Error>> {
+    let legacy = legacy_paths(mirror.site)
+        .into_iter()
+        .filter(|path| Path::new(path).exists())
+        .collect::<Vec<_>>();
+    if legacy.is_empty() {
+        return Ok(());
+    }
+
+    let mut state = mirror.state().clone();
+    for (category, category_state) in state.categories.iter_mut() {
+        let untitled = category_state
+            .tasks
+            .iter()
+            .filter(|task| task.title.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+        let pages = page_info(mirror.transport, &untitled)?;
+        for task in category_state.tasks.iter_mut() {
+            let page = pages
+                .iter()
+                .find(|page| page.pageid == task.pageid && !page.title.is_empty());
+            if let Some(page) = page {
+                let directory = write_code_onig::task_directory(category, &page.title)?;
+                task.files = write_code_onig::programs(&directory)?;
+                task.title = page.title.clone();
+                task.directory = directory;
+            }
+        }
+    }
+    info!("moving mirror state out of the tree");
+    *mirror.state() = state;
+    for path in legacy {
+        fs::remove_file(&path).map_err(RosettaError::file(&path))?;
+    }
+    commit_changes(mirror, "moved mirror state out of the tree")
+}
+
+fn write_task_tally(mirror: &Mirror, written_tasks: &HashSet<WrittenTask>, directory: &str) {
+    mirror.state().set_tasks(directory, written_tasks);
+}
+
+// None for a category that hasn't been mirrored yet
+fn read_task_tally(mirror: &Mirror, directory: &str) -> Option<HashSet<WrittenTask>> {
+    mirror.state().tasks(directory)
+}
+
+// revid of the tallied revision of a page, if the page is tallied
+fn tallied_revid(task_set: &HashSet<WrittenTask>, pageid: u64) -> Option<u64> {
+    task_set
         .iter()
-        .flat_map(|task| write_task(lan, directory, task))
-        .collect()
+        .find(|task| task.pageid == pageid)
+        .map(|task| task.revid)
 }
 
-fn write_task_tally(
-    written_tasks: &HashSet<WrittenTask>,
+fn write_failed_tasks(mirror: &Mirror, failed: &[Task], directory: &str) {
+    mirror.state().set_failed(directory, failed);
+}
+
+fn read_failed_tasks(mirror: &Mirror, directory: &str) -> Vec<Task> {
+    mirror.state().failed(directory)
+}
+
+fn add_failed_task(mirror: &Mirror, directory: &str, task: Task) {
+    let mut failed = read_failed_tasks(mirror, directory);
+    if !failed.iter().any(|t| t.pageid == task.pageid) {
+        failed.push(task);
+        write_failed_tasks(mirror, &failed, directory);
+    }
+}
+
+// Commit every revision of a task in turn, as if the mirror had been
+// following it all along.
+fn backfill_task(
+    mirror: &Mirror,
     directory: &str,
-) -> Result<(), Box<dyn Error>> {
-    let f = File::create(directory.to_string() + "/tasks")?;
-    let mut b = BufWriter::new(f);
-    let s = serde_json::to_string(&written_tasks)?;
-    b.write_all(s.as_bytes())?;
+    task: &Task,
+) -> Result<Option<WrittenTask>, Box<dyn Error>> {
+    let mut latest = None;
+    query_parts(
+        mirror.transport,
+        make_history_query_args(task),
+        |part: Pages| {
+            for page in part.pages {
+                for rd in page.revisions {
+                    let files = write_code_onig::write_code(
+                        mirror.lan,
+                        &mirror.site.header_template,
+                        directory,
+                        &page.title,
+                        &rd.content,
+                    )?;
+                    let written =
+                        WrittenTask::new(directory, page.pageid, &page.title, rd.revid, files)?;
+                    if let Some(previous) = &latest {
+                        remove_stale_programs(previous, &written)?;
+                    }
+                    latest = Some(written);
+                    let modified = diff_names(mirror, directory)?;
+                    if !modified.is_empty() {
+                        let comment_arg = format!(
+                            "task: {}\nuser: {}\ncomment: {}\ntimestamp: {}\nmodified: {}\n",
+                            page.title, rd.user, rd.comment, rd.timestamp, modified
+                        );
+                        commit_wiki_change(mirror, &comment_arg, &rd.user, &rd.timestamp)?;
+                    }
+                }
+            }
+            Ok(())
+        },
+    )?;
+    Ok(latest)
+}
+
+fn backfill_tasks(
+    mirror: &Mirror,
+    tasks: &[Task],
+    directory: &str,
+) -> Result<(HashSet<WrittenTask>, Vec<Task>), Box<dyn Error>> {
+    let mut written = HashSet::new();
+    let mut failed = vec![];
+    for task in tasks {
+        // a revision that fails to be written or committed mustn't be
+        // swept into the next commit along with the following task
+        match transaction(mirror, || backfill_task(mirror, directory, task)) {
+            Ok(Some(wt)) => {
+                // only the programs the task ends up with, not every
+                // revision's
+                mirror.report.files_written(&wt.files);
+                mirror.report.task_added(directory, &task.title);
+                written.insert(wt);
+            }
+            // deleted since the category was listed
+            Ok(None) => {}
+            Err(e) => {
+                error!(category = directory, task = task.title.as_str(); "backfill failed: {}", e);
+                mirror.report.fetch_failed(directory, &task.title);
+                failed.push(task.clone());
+            }
+        }
+    }
+    Ok((written, failed))
+}
+
+fn initialize_tasks(mirror: &Mirror, category_name: &str) -> Result<(), Box<dyn Error>> {
+    let tasks: Tasks = query(mirror.transport, make_category_query_args(category_name))?;
+    let (written_tasks, failed_tasks) = if mirror.options.backfill {
+        backfill_tasks(mirror, &tasks.categorymembers, category_name)?
+    } else {
+        let (written, failed) = write_tasks(mirror, &tasks.categorymembers, category_name);
+        report_written_tasks(mirror, category_name, &HashSet::new(), &written);
+        (written, failed)
+    };
+    // a category may be empty on a smaller wiki but still gets a directory
+    fs::DirBuilder::new()
+        .recursive(true)
+        .create(category_name)?;
+    write_task_tally(mirror, &written_tasks, category_name);
+    write_failed_tasks(mirror, &failed_tasks, category_name);
+    commit_changes(mirror, &(category_name.to_string() + ": initial commit"))?;
+    Ok(())
+}
+
+fn resync_tasks(mirror: &Mirror, category_name: &str) -> Result<(), Box<dyn Error>> {
+    let tasks: Tasks = query(mirror.transport, make_category_query_args(category_name))?;
+    // start from an empty tree so that nothing stale survives the resync
+    if Path::new(category_name).exists() {
+        fs::remove_dir_all(category_name)?;
+    }
+    fs::DirBuilder::new()
+        .recursive(true)
+        .create(category_name)?;
+    let (written_tasks, failed_tasks) = write_tasks(mirror, &tasks.categorymembers, category_name);
+    let previous = read_task_tally(mirror, category_name).unwrap_or_default();
+    report_written_tasks(mirror, category_name, &previous, &written_tasks);
+    write_task_tally(mirror, &written_tasks, category_name);
+    write_failed_tasks(mirror, &failed_tasks, category_name);
     Ok(())
 }
 
-fn read_task_tally(directory: &str) -> Result<HashSet<WrittenTask>, Box<dyn Error>> {
-    let f = File::open(directory.to_string() + "/tasks")?;
-    let mut b = BufReader::new(f);
-    let mut s = String::new();
-    b.read_to_string(&mut s)?;
-    let v: Value = serde_json::from_str(&s)?;
-    let revi = <HashSet<WrittenTask>>::deserialize(v)?;
+// Try again to fetch the tasks that failed on earlier runs, so that they
+// eventually make it into the tally rather than being lost.
+fn retry_failed_tasks(mirror: &Mirror, category_name: &str) -> Result<(), Box<dyn Error>> {
+    let failed = read_failed_tasks(mirror, category_name);
+    if failed.is_empty() {
+        return Ok(());
+    }
 
-    Ok(revi)
+    let (written_tasks, still_failed) = write_tasks(mirror, &failed, category_name);
+    let mut task_set = read_task_tally(mirror, category_name).unwrap_or_default();
+    report_written_tasks(mirror, category_name, &task_set, &written_tasks);
+    replace_tasks(&mut task_set, written_tasks)?;
+    write_task_tally(mirror, &task_set, category_name);
+    write_failed_tasks(mirror, &still_failed, category_name);
+    commit_changes(
+        mirror,
+        &format!(
+            "{}: retried {} failed tasks, {} still failing",
+            category_name,
+            failed.len(),
+            still_failed.len()
+        ),
+    )
 }
 
-fn initialize_tasks(
-    lan: &languages::Langs,
-    category_name: &str,
+fn diff_names(mirror: &Mirror, directory: &str) -> Result<String, Box<dyn Error>> {
+    Ok(mirror.repo.staged_paths(directory)?.join("\n"))
+}
+
+fn commit_changes(mirror: &Mirror, comment: &str) -> Result<(), Box<dyn Error>> {
+    commit_as(mirror, comment, None)
+}
+
+// Commit a change made on the wiki as its author, at the time it was
+// made there, so that git log and git blame reflect the wiki's history.
+fn commit_wiki_change(
+    mirror: &Mirror,
+    comment: &str,
+    user: &str,
+    timestamp: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let tasks: Tasks = query(make_category_query_args(category_name))?;
-    let written_tasks = write_tasks(&tasks, &lan, category_name);
-    write_task_tally(&written_tasks, category_name)?;
-    init_repo(category_name)?;
-    Ok(())
+    // the user is hidden from the API when suppressed on the wiki
+    let name = if user.is_empty() {
+        "(username removed)"
+    } else {
+        user
+    };
+    // git needs an email but the wiki doesn't give out its users'
+    // addresses, so the author gets one under the reserved .invalid
+    // domain that can't reach anyone
+    let host = url::Url::parse(&mirror.site.endpoint)?
+        .host_str()
+        .unwrap_or_default()
+        .to_owned();
+    let author = Author {
+        name: name.to_owned(),
+        email: name.replace(' ', "_") + "@" + &host + ".invalid",
+        time: chrono::DateTime::parse_from_rfc3339(timestamp)?.timestamp(),
+    };
+    commit_as(mirror, comment, Some(&author))
 }
 
-fn diff_names(directory: &str) -> Result<String, Box<dyn Error>> {
-    Command::new("git").arg("add").arg(directory).output()?;
+fn commit_as(
+    mirror: &Mirror,
+    comment: &str,
+    author: Option<&Author>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(hash) = mirror.repo.commit(comment, author)? {
+        info!(commit = hash.as_str(); "{}", comment.lines().next().unwrap_or_default());
+        mirror.report.commit(&hash);
+        store_state(mirror)?;
+    }
+    Ok(())
+}
 
-    let output = Command::new("git")
-        .arg("diff")
-        .arg("--name-only")
-        .arg("--cached")
-        .arg(directory)
-        .output()?;
+// Remove the programs an earlier revision of a task produced that the
+// one just written no longer does, and any language directory that
+// leaves empty.
+fn remove_stale_programs(
+    previous: &WrittenTask,
+    written: &WrittenTask,
+) -> Result<(), Box<dyn Error>> {
+    for file in previous
+        .files
+        .iter()
+        .filter(|file| !written.files.contains(file))
+    {
+        if !Path::new(file).exists() {
+            continue;
+        }
+        debug!(task = written.title.as_str(); "removing {}", file);
+        fs::remove_file(file).map_err(RosettaError::file(file))?;
+        if let Some(lang_dir) = Path::new(file).parent() {
+            let _ = fs::remove_dir(lang_dir);
+        }
+    }
+    Ok(())
+}
 
-    let ostr = str::from_utf8(&output.stdout)?;
-    Ok(ostr.to_string())
+// Put newly written tasks in the tally in place of the revisions they
+// were written over.
+fn replace_tasks(
+    task_set: &mut HashSet<WrittenTask>,
+    written_tasks: impl IntoIterator<Item = WrittenTask>,
+) -> Result<(), Box<dyn Error>> {
+    for written in written_tasks {
+        let previous = task_set
+            .iter()
+            .find(|task| task.pageid == written.pageid)
+            .cloned();
+        if let Some(previous) = previous {
+            remove_stale_programs(&previous, &written)?;
+            task_set.remove(&previous);
+        }
+        task_set.insert(written);
+    }
+    Ok(())
 }
 
-fn commit_changes(comment: &str) -> Result<(), Box<dyn Error>> {
-    Command::new("git").arg("add").arg(".").output()?;
-    Command::new("git")
-        .arg("commit")
-        .arg("-m")
-        .arg(comment)
-        .output()?;
+// Move a file or directory, creating the directories leading to its new
+// path. The next commit records it as a rename.
+fn rename_path(from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = Path::new(to).parent() {
+        fs::DirBuilder::new().recursive(true).create(parent)?;
+    }
+    fs::rename(from, to).map_err(RosettaError::file(from))?;
     Ok(())
 }
 
-fn init_repo(directory: &str) -> Result<(), Box<dyn Error>> {
-    Command::new("git").arg("init").output()?;
-    commit_changes(&(directory.to_string() + ": initial commit"))
+fn latest_change(transport: &dyn MediaWikiTransport) -> Result<Option<SyncCursor>, Box<dyn Error>> {
+    Ok(first_change(transport, make_latest_change_query_args())?
+        .map(|revision| SyncCursor::new(&revision.timestamp, revision.rcid)))
 }
 
-fn read_revision_timestamp() -> Result<String, Box<dyn Error>> {
-    let f = File::open("revision_timestamp")?;
-    let mut b = BufReader::new(f);
-    let mut s = String::new();
-    b.read_to_string(&mut s)?;
-    let v: Value = serde_json::from_str(&s)?;
-    let ts = <String>::deserialize(v)?;
+fn oldest_change_timestamp(
+    transport: &dyn MediaWikiTransport,
+) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(
+        first_change(transport, make_oldest_change_query_args())?
+            .map(|revision| revision.timestamp),
+    )
+}
 
-    Ok(ts)
+fn first_change(
+    transport: &dyn MediaWikiTransport,
+    args: Vec<(String, String)>,
+) -> Result<Option<Revision>, Box<dyn Error>> {
+    let s = transport.get(&args)?;
+    let v = parse_response(&s, &args)?;
+    let changes = Revisions::deserialize(&v["query"])?;
+    Ok(changes.recentchanges.into_iter().next())
 }
 
-fn save_revision_timestamp(ts: &str) -> Result<(), Box<dyn Error>> {
-    let f = File::create("revision_timestamp")?;
-    let mut b = BufWriter::new(f);
-    let s = serde_json::to_string(ts)?;
-    b.write_all(s.as_bytes())?;
-    Ok(())
+// Where to carry on following recent changes from.
+fn read_sync_cursor(mirror: &Mirror) -> Option<SyncCursor> {
+    mirror.state().cursor.clone()
+}
+
+// The cursor moves on with every change dealt with, whether or not that
+// change made a commit; the state is written with the next commit, or at
+// the end of the run.
+fn save_sync_cursor(mirror: &Mirror, cursor: &SyncCursor) {
+    mirror.state().cursor = Some(cursor.clone());
+}
+
+fn revision_cursor(revision: &Revision) -> SyncCursor {
+    SyncCursor::new(&revision.timestamp, revision.rcid)
+}
+
+// Apply a change to the mirror as a whole. If anything fails before it
+// is committed, the working tree goes back to the last commit, so that
+// nothing half done is swept into the next, and the state to what it
+// was before the change, keeping the progress made since that commit.
+fn transaction<T>(
+    mirror: &Mirror,
+    f: impl FnOnce() -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let state = mirror.state().clone();
+    let result = f();
+    if result.is_err() {
+        mirror.repo.rollback()?;
+        *mirror.state() = state;
+    }
+    result
+}
+
+// A transaction on a category's tally as the caller holds it, which is
+// put back along with everything else.
+fn tally_transaction<T>(
+    mirror: &Mirror,
+    task_set: &mut HashSet<WrittenTask>,
+    f: impl FnOnce(&mut HashSet<WrittenTask>) -> Result<T, Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    let mut changed = task_set.clone();
+    let result = transaction(mirror, || f(&mut changed))?;
+    *task_set = changed;
+    Ok(result)
 }
 
 fn process_revision(
-    lan: &languages::Langs,
+    mirror: &Mirror,
+    directory: &str,
+    revision: &Revision,
+    task_set: &mut HashSet<WrittenTask>,
+) -> Result<(), Box<dyn Error>> {
+    debug!(task = revision.title.as_str(), revid = revision.revid; "recent change");
+    if revision.is_log("delete", "delete") {
+        return tally_transaction(mirror, task_set, |task_set| {
+            process_deletion(mirror, directory, revision, task_set)
+        });
+    }
+    if let Some(target) = revision.move_target() {
+        return tally_transaction(mirror, task_set, |task_set| {
+            process_move(mirror, directory, revision, target, task_set)
+        });
+    }
+
+    if revision.revid != revision.old_revid
+        && tallied_revid(task_set, revision.pageid) == Some(revision.old_revid)
+    {
+        // a page that can't be fetched or written is left to the failed
+        // list, but a failed commit stops the sync before the cursor moves
+        // past it
+        let written = transaction(mirror, || write_revision(mirror, directory, revision));
+        let (written_task, timestamp, user, comment, title) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                error!(category = directory, task = revision.title.as_str(); "revision failed: {}", e);
+                add_failed_task(
+                    mirror,
+                    directory,
+                    Task {
+                        pageid: revision.pageid,
+                        title: revision.title.clone(),
+                    },
+                );
+                return Ok(());
+            }
+        };
+        tally_transaction(mirror, task_set, |task_set| {
+            replace_tasks(task_set, [written_task])?;
+            // the new revid is kept even if only prose changed, or the
+            // next edit of the page would no longer follow on from it
+            write_task_tally(mirror, task_set, directory);
+            let modified = diff_names(mirror, directory)?;
+            if !modified.is_empty() {
+                let comment_arg = format!(
+                    "task: {}\nuser: {}\ncomment: {}\ntimestamp: {}\nmodified: {}\n",
+                    title, user, comment, timestamp, modified
+                );
+                commit_wiki_change(mirror, &comment_arg, &user, &timestamp)?;
+                mirror.report.task_updated(directory, &title);
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+// Remove a deleted page's programs and tally entry. The log event may
+// not carry the pageid of a page that no longer exists, so the task is
+// also found by its title. Only a tallied task is removed: other titles
+// can map to the same directory, and deleting one of them, such as a
+// redirect, mustn't take a live task with it.
+fn process_deletion(
+    mirror: &Mirror,
+    directory: &str,
+    revision: &Revision,
+    task_set: &mut HashSet<WrittenTask>,
+) -> Result<(), Box<dyn Error>> {
+    let deleted = task_set
+        .iter()
+        .filter(|task| task.pageid == revision.pageid || same_title(&task.title, &revision.title))
+        .cloned()
+        .collect::<Vec<_>>();
+    if deleted.is_empty() {
+        return Ok(());
+    }
+
+    info!(category = directory, task = revision.title.as_str(); "task deleted");
+    for task in &deleted {
+        remove_task(task_set, task)?;
+    }
+    let comment_arg = format!(
+        "deleted task: {}\nuser: {}\ncomment: {}\ntimestamp: {}\n",
+        revision.title, revision.user, revision.comment, revision.timestamp
+    );
+    write_task_tally(mirror, task_set, directory);
+    commit_wiki_change(mirror, &comment_arg, &revision.user, &revision.timestamp)?;
+    mirror.report.task_deleted(directory, &revision.title);
+    Ok(())
+}
+
+// Follow a page move by moving the task's directory and files to the
+// names its new title gives them. Committed together, git sees them as
+// renames, so the task's history carries over.
+fn process_move(
+    mirror: &Mirror,
     directory: &str,
     revision: &Revision,
+    target: &str,
     task_set: &mut HashSet<WrittenTask>,
 ) -> Result<(), Box<dyn Error>> {
-    let current_task = WrittenTask::new(revision.pageid, revision.revid);
-    let old_task = WrittenTask::new(revision.pageid, revision.old_revid);
-    if task_set.contains(&old_task) && !task_set.contains(&current_task) {
-        let (written_task, timestamp, user, comment, title) =
-            write_revision(&lan, directory, revision)?;
-        task_set.remove(&old_task);
-        task_set.insert(written_task);
-        let modified = diff_names(directory)?;
-        if modified != "" {
-            let comment_arg = format!(
-                "task: {}\nuser: {}\ncomment: {}\ntimestamp: {}\nmodified: {}\n",
-                title, user, comment, timestamp, modified
-            );
-            write_task_tally(task_set, directory)?;
-            save_revision_timestamp(&revision.timestamp)?;
-            commit_changes(&comment_arg)?;
+    let old_dir = write_code_onig::task_directory(directory, &revision.title)?;
+    let new_dir = write_code_onig::task_directory(directory, target)?;
+    let mirrored = old_dir != new_dir && Path::new(&old_dir).exists();
+    let tallied = task_set
+        .iter()
+        .find(|task| task.pageid == revision.pageid)
+        .cloned();
+    // a task with no programs has nothing to rename, but its tally entry
+    // still follows the page
+    let tallied = tallied.filter(|task| mirrored || !same_title(&task.title, target));
+    if !mirrored && tallied.is_none() {
+        return Ok(());
+    }
+
+    info!(category = directory, task = target, from = revision.title.as_str(); "task renamed");
+    let mut renames = vec![];
+    if mirrored {
+        renames = write_code_onig::renamed_programs(directory, &revision.title, target)?;
+        for (from, to) in &renames {
+            rename_path(from, to)?;
         }
+        // moving the files leaves their directories behind
+        for lang_dir in fs::read_dir(&old_dir)? {
+            let _ = fs::remove_dir(lang_dir?.path());
+        }
+        let _ = fs::remove_dir(&old_dir);
+    }
+
+    if let Some(mut moved) = tallied {
+        task_set.remove(&moved);
+        // the move adds a revision to the page, which the next edit builds
+        // on, unless the tally has already caught up with a later one
+        moved.revid = moved.revid.max(revision.revid);
+        moved.title = target.to_owned();
+        moved.directory = new_dir;
+        for file in moved.files.iter_mut() {
+            if let Some((_, to)) = renames.iter().find(|(from, _)| from == file) {
+                *file = to.clone();
+            }
+        }
+        task_set.insert(moved);
+    }
+
+    let comment_arg = format!(
+        "renamed task: {} -> {}\nuser: {}\ncomment: {}\ntimestamp: {}\n",
+        revision.title, target, revision.user, revision.comment, revision.timestamp
+    );
+    write_task_tally(mirror, task_set, directory);
+    commit_wiki_change(mirror, &comment_arg, &revision.user, &revision.timestamp)?;
+    mirror
+        .report
+        .task_renamed(directory, &revision.title, target);
+    Ok(())
+}
+
+// Drop a task from the tally along with its programs.
+fn remove_task(
+    task_set: &mut HashSet<WrittenTask>,
+    task: &WrittenTask,
+) -> Result<(), Box<dyn Error>> {
+    task_set.remove(task);
+    if Path::new(&task.directory).exists() {
+        fs::remove_dir_all(&task.directory).map_err(RosettaError::file(&task.directory))?;
     }
     Ok(())
 }
 
-fn update_new_tasks(
-    lan: &languages::Langs,
+// the API reports titles with spaces where we may have underscores
+fn same_title(a: &str, b: &str) -> bool {
+    a.replace('_', " ") == b.replace('_', " ")
+}
+
+// Those of the given tasks that are currently members of the category.
+fn tasks_in_category(
+    transport: &dyn MediaWikiTransport,
+    tasks: &[Task],
+    category_name: &str,
+) -> Result<Vec<Task>, Box<dyn Error>> {
+    let category_title = "Category:".to_owned() + category_name;
+    let mut members = vec![];
+    for batch in tasks.chunks(TASK_BATCH_SIZE) {
+        let pages: Pages = query(
+            transport,
+            make_category_check_query_args(batch, category_name),
+        )?;
+        members.extend(
+            pages
+                .pages
+                .into_iter()
+                .filter(|page| {
+                    page.categories
+                        .iter()
+                        .any(|category| same_title(&category.title, &category_title))
+                })
+                .map(|page| Task {
+                    pageid: page.pageid,
+                    title: page.title,
+                }),
+        );
+    }
+    Ok(members)
+}
+
+// Pages created or edited since the last run that are not in the tally
+// may be new tasks, or existing pages that were added to the category.
+fn add_new_tasks(
+    mirror: &Mirror,
     directory: &str,
-    tasks: &HashSet<WrittenTask>,
+    task_set: &mut HashSet<WrittenTask>,
     rc: &[Revision],
 ) -> Result<(), Box<dyn Error>> {
-    let mut task_set: HashSet<WrittenTask> = tasks.clone();
+    let mut failed = read_failed_tasks(mirror, directory);
+    let mut candidates: Vec<Task> = vec![];
+    for revision in rc {
+        let known = |pageid| {
+            task_set.iter().any(|task| task.pageid == pageid)
+                || failed.iter().any(|task| task.pageid == pageid)
+                || candidates.iter().any(|task| task.pageid == pageid)
+        };
+        if (revision.kind == "new" || revision.kind == "edit")
+            && revision.pageid != 0
+            && !known(revision.pageid)
+        {
+            candidates.push(Task {
+                pageid: revision.pageid,
+                title: revision.title.clone(),
+            });
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(());
+    }
 
-    let _u = rc
+    let added = tasks_in_category(mirror.transport, &candidates, directory)?;
+    if added.is_empty() {
+        return Ok(());
+    }
+    let (written_tasks, failed_tasks) = write_tasks(mirror, &added, directory);
+    report_written_tasks(mirror, directory, task_set, &written_tasks);
+    task_set.extend(written_tasks);
+    failed.extend(failed_tasks);
+    write_task_tally(mirror, task_set, directory);
+    write_failed_tasks(mirror, &failed, directory);
+
+    let titles = added
+        .iter()
+        .map(|task| task.title.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    commit_changes(
+        mirror,
+        &format!("{}: added {} tasks\n\n{}\n", directory, added.len(), titles),
+    )
+}
+
+// Apply each recent change to every category in turn, recording it as
+// dealt with only once all of them have: categories overlap, and one
+// that fails must find the change again next run. The state written
+// with an earlier category's commit still has the cursor before the
+// change, which that category then skips as already tallied. A change
+// that only touched prose still moves the cursor on, so it isn't read
+// again next run. New tasks are looked for first: if that fails the
+// cursor hasn't moved past the pages that created them.
+fn update_tasks(
+    mirror: &Mirror,
+    tallies: &mut [(&str, HashSet<WrittenTask>)],
+    rc: &[Revision],
+) -> Result<(), Box<dyn Error>> {
+    for (category_name, task_set) in tallies.iter_mut() {
+        tally_transaction(mirror, task_set, |task_set| {
+            add_new_tasks(mirror, category_name, task_set, rc)
+        })?;
+    }
+
+    for revision in rc {
+        for (category_name, task_set) in tallies.iter_mut() {
+            process_revision(mirror, category_name, revision, task_set)?;
+        }
+        save_sync_cursor(mirror, &revision_cursor(revision));
+    }
+
+    for (category_name, _) in tallies.iter() {
+        transaction(mirror, || retry_failed_tasks(mirror, category_name))?;
+    }
+    Ok(())
+}
+
+fn full_resync(mirror: &Mirror) -> Result<(), Box<dyn Error>> {
+    // take the cursor before fetching so that edits made during the
+    // resync are picked up again by the next incremental run.
+    let latest = latest_change(mirror.transport)?;
+
+    mirror
+        .site
+        .task_categories
         .iter()
-        .flat_map(|revision| process_revision(lan, directory, revision, &mut task_set))
+        .map(|category| resync_tasks(mirror, category))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(cursor) = latest {
+        save_sync_cursor(mirror, &cursor);
+    }
+    commit_changes(mirror, "full resync")
+}
+
+// A tallied task as it is once its directory has moved to another
+// category's tree, listing every program now there.
+fn moved_task(task: WrittenTask, title: &str, to: &str) -> Result<WrittenTask, Box<dyn Error>> {
+    let new_dir = write_code_onig::task_directory(to, title)?;
+    let files = write_code_onig::programs(&new_dir)?;
+    WrittenTask::new(to, task.pageid, title, task.revid, files)
+}
+
+// Move a task's programs from one category's tree to another's. If the
+// other tree already has a directory for the task, left there by an
+// earlier run, the two are merged, with the task's own programs replacing
+// any of the same name.
+fn move_task_directory(from: &str, to: &str, title: &str) -> Result<(), Box<dyn Error>> {
+    let old_dir = write_code_onig::task_directory(from, title)?;
+    let new_dir = write_code_onig::task_directory(to, title)?;
+    if !Path::new(&old_dir).exists() {
+        return Ok(());
+    }
+    if !Path::new(&new_dir).exists() {
+        return rename_path(&old_dir, &new_dir);
+    }
+    for program in write_code_onig::programs(&old_dir)? {
+        let target = new_dir.clone() + &program[old_dir.len()..];
+        rename_path(&program, &target)?;
+    }
+    fs::remove_dir_all(&old_dir).map_err(RosettaError::file(&old_dir))?;
+    Ok(())
+}
+
+// Compare each category's tally with its current members. A task that
+// has left one category for another since the last run, as a draft does
+// when it is promoted, is moved across rather than fetched again.
+fn move_recategorized_tasks(mirror: &Mirror) -> Result<(), Box<dyn Error>> {
+    let mut categories = vec![];
+    for category in &mirror.site.task_categories {
+        if let Some(tally) = read_task_tally(mirror, category) {
+            let members: Tasks = query(mirror.transport, make_category_query_args(category))?;
+            categories.push((category.as_str(), tally, members.categorymembers));
+        }
+    }
+
+    let member =
+        |members: &[Task], pageid| members.iter().find(|task| task.pageid == pageid).cloned();
+    for from in 0..categories.len() {
+        for to in 0..categories.len() {
+            if from == to {
+                continue;
+            }
+            let (from_name, from_tally, from_members) = &categories[from];
+            let (to_name, to_tally, to_members) = &categories[to];
+            let moves = from_tally
+                .iter()
+                .filter(|task| member(from_members, task.pageid).is_none())
+                .filter(|task| !to_tally.iter().any(|t| t.pageid == task.pageid))
+                .filter_map(|task| Some((task.clone(), member(to_members, task.pageid)?.title)))
+                .collect::<Vec<_>>();
+            let (from_name, to_name) = (*from_name, *to_name);
+
+            for (task, title) in moves {
+                transaction(mirror, || {
+                    move_task_directory(from_name, to_name, &title)?;
+                    info!(task = title.as_str(), from = from_name, to = to_name; "task moved");
+                    categories[from].1.remove(&task);
+                    categories[to].1.insert(moved_task(task, &title, to_name)?);
+                    write_task_tally(mirror, &categories[from].1, from_name);
+                    write_task_tally(mirror, &categories[to].1, to_name);
+                    commit_changes(
+                        mirror,
+                        &format!(
+                            "moved task: {}\nfrom: {}\nto: {}\n",
+                            title, from_name, to_name
+                        ),
+                    )
+                })?;
+                mirror.report.task_moved(&title, from_name, to_name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Current state of the given tasks' pages, including their latest revid.
+fn page_info(
+    transport: &dyn MediaWikiTransport,
+    tasks: &[WrittenTask],
+) -> Result<Vec<PageDetail>, Box<dyn Error>> {
+    let mut pages = vec![];
+    for batch in tasks.chunks(TASK_BATCH_SIZE) {
+        let info: Pages = query(transport, make_info_query_args(batch))?;
+        pages.extend(info.pages);
+    }
+    Ok(pages)
+}
+
+// Without recent changes to go by, compare the tally against the wiki:
+// refetch every task whose page has a newer revision than the one we
+// wrote, along with members of the category we don't have at all, and
+// remove those whose page has been deleted.
+fn refetch_stale_tasks(mirror: &Mirror, category_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut task_set = match read_task_tally(mirror, category_name) {
+        Some(tasks) => tasks,
+        None => return Ok(()),
+    };
+    let tallied = task_set.iter().cloned().collect::<Vec<_>>();
+    let (missing, pages): (Vec<_>, Vec<_>) = page_info(mirror.transport, &tallied)?
+        .into_iter()
+        .partition(|page| page.missing);
+    let deleted = tallied
+        .into_iter()
+        .filter(|task| missing.iter().any(|page| page.pageid == task.pageid))
+        .collect::<Vec<_>>();
+    for task in &deleted {
+        info!(category = category_name, task = task.title.as_str(); "task deleted");
+        remove_task(&mut task_set, task)?;
+        mirror.report.task_deleted(category_name, &task.title);
+    }
+    let mut stale = pages
+        .into_iter()
+        .filter(|page| tallied_revid(&task_set, page.pageid) != Some(page.lastrevid))
+        .map(|page| Task {
+            pageid: page.pageid,
+            title: page.title,
+        })
         .collect::<Vec<_>>();
+    let members: Tasks = query(mirror.transport, make_category_query_args(category_name))?;
+    stale.extend(
+        members
+            .categorymembers
+            .into_iter()
+            .filter(|member| !task_set.iter().any(|task| task.pageid == member.pageid)),
+    );
+    if stale.is_empty() && deleted.is_empty() {
+        return Ok(());
+    }
+
+    let (written_tasks, failed_tasks) = write_tasks(mirror, &stale, category_name);
+    report_written_tasks(mirror, category_name, &task_set, &written_tasks);
+    replace_tasks(&mut task_set, written_tasks)?;
+    let mut failed = read_failed_tasks(mirror, category_name);
+    failed.extend(failed_tasks);
+    write_task_tally(mirror, &task_set, category_name);
+    write_failed_tasks(mirror, &failed, category_name);
+    commit_changes(
+        mirror,
+        &format!(
+            "{}: refetched {} tasks and removed {} deleted while recent changes were not followed",
+            category_name,
+            stale.len(),
+            deleted.len()
+        ),
+    )
+}
+
+// The cursor to follow recent changes from. When the last run was so
+// long ago that the wiki no longer has the changes since then, bring the
+// tallies up to date by other means and continue from the newest change.
+fn recent_changes_start(mirror: &Mirror) -> Result<Option<SyncCursor>, Box<dyn Error>> {
+    let cursor = match read_sync_cursor(mirror) {
+        Some(cursor) => cursor,
+        None => return Ok(None),
+    };
+    let timestamp = cursor.timestamp.as_str();
+    let latest = latest_change(mirror.transport)?;
+    let oldest = oldest_change_timestamp(mirror.transport)?;
+    match (oldest, latest) {
+        (Some(oldest), Some(latest)) if cursor.position()? < change_position(&oldest, 0)? => {
+            warn!(timestamp = timestamp, oldest = oldest.as_str();
+                "recent changes no longer reach back to the last run, comparing revisions instead");
+            mirror
+                .site
+                .task_categories
+                .iter()
+                .map(|category| transaction(mirror, || refetch_stale_tasks(mirror, category)))
+                .collect::<Result<Vec<_>, _>>()?;
+            save_sync_cursor(mirror, &latest);
+            Ok(Some(latest))
+        }
+        _ => Ok(Some(cursor)),
+    }
+}
+
+// Compare a category's tally with the wiki, returning the number of
+// stale, missing and extra tasks.
+fn verify_tasks(
+    mirror: &Mirror,
+    category_name: &str,
+) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    let task_set = read_task_tally(mirror, category_name).unwrap_or_default();
+    let members: Tasks = query(mirror.transport, make_category_query_args(category_name))?;
+    let is_member = |pageid| {
+        members
+            .categorymembers
+            .iter()
+            .any(|task| task.pageid == pageid)
+    };
+
+    let (mut stale, mut missing, mut extra) = (0, 0, 0);
+    let tallied = task_set.iter().cloned().collect::<Vec<_>>();
+    for page in page_info(mirror.transport, &tallied)? {
+        let title = if page.title.is_empty() {
+            format!("pageid {}", page.pageid)
+        } else {
+            page.title
+        };
+        if page.missing || !is_member(page.pageid) {
+            warn!(category = category_name, task = title.as_str(); "extra task");
+            mirror.report.task_extra(category_name, &title);
+            extra += 1;
+        } else if tallied_revid(&task_set, page.pageid) != Some(page.lastrevid) {
+            warn!(category = category_name, task = title.as_str(), revid = page.lastrevid; "stale task");
+            mirror.report.task_stale(category_name, &title);
+            stale += 1;
+        }
+    }
+    for member in &members.categorymembers {
+        if !task_set.iter().any(|task| task.pageid == member.pageid) {
+            warn!(category = category_name, task = member.title.as_str(); "missing task");
+            mirror.report.task_missing(category_name, &member.title);
+            missing += 1;
+        }
+    }
+    Ok((stale, missing, extra))
+}
+
+fn verify(mirror: &Mirror) -> Result<(), Box<dyn Error>> {
+    let (mut stale, mut missing, mut extra) = (0, 0, 0);
+    for category in &mirror.site.task_categories {
+        let (s, m, e) = verify_tasks(mirror, category)?;
+        stale += s;
+        missing += m;
+        extra += e;
+    }
+    if stale + missing + extra > 0 {
+        return Err(Box::new(RosettaError::Drift {
+            stale,
+            missing,
+            extra,
+        }));
+    }
+    info!("mirror is up to date");
     Ok(())
 }
 
-fn update_tasks<'a>(
-    lan: &languages::Langs,
-    category_name: &'a str,
-    rc: &[Revision],
-) -> Result<Option<&'a str>, Box<dyn Error>> {
-    match read_task_tally(category_name) {
-        Ok(tasks) => { 
-            update_new_tasks(lan, category_name, &tasks, &rc)?; 
-            Ok(None)
-        },
-        _ => Ok(Some(category_name))
+fn sync(mirror: &Mirror) -> Result<(), Box<dyn Error>> {
+    if mirror.options.verify {
+        return verify(mirror);
     }
+    migrate_state(mirror)?;
+    if mirror.options.full_resync {
+        full_resync(mirror)?;
+        return store_state(mirror);
+    }
+
+    // get previous cursor (if it exists).
+    let cursor = recent_changes_start(mirror)?;
+    let timestamp = cursor.as_ref().map(|cursor| cursor.timestamp.clone());
+    let revisions: Revisions = query(mirror.transport, make_recentchanges_query_args(timestamp))?;
+    let mut rc = revisions
+        .recentchanges
+        .into_iter()
+        .map(|revision| {
+            Ok((
+                change_position(&revision.timestamp, revision.rcid)?,
+                revision,
+            ))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    rc.sort_by_key(|(position, _)| *position);
+    // a change can turn up on more than one page of results, and rcstart
+    // includes changes made in the same second as the last one dealt with
+    rc.dedup_by_key(|(position, _)| *position);
+    if let Some(cursor) = &cursor {
+        let start = cursor.position()?;
+        rc.retain(|(position, _)| *position > start);
+    }
+    let rc = rc
+        .into_iter()
+        .map(|(_, revision)| revision)
+        .collect::<Vec<_>>();
+    if let (Some(first), Some(last)) = (rc.first(), rc.last()) {
+        mirror
+            .report
+            .changes_consumed(&first.timestamp, &last.timestamp);
+    }
+
+    move_recategorized_tasks(mirror)?;
+
+    // do updates first so that timestamp gets set before reading tasks for new category.
+    let mut tallies = vec![];
+    let mut new_categories = vec![];
+    for category in &mirror.site.task_categories {
+        match read_task_tally(mirror, category) {
+            Some(task_set) => tallies.push((category.as_str(), task_set)),
+            None => new_categories.push(category.as_str()),
+        }
+    }
+    update_tasks(mirror, &mut tallies, &rc)?;
+
+    for category in new_categories {
+        transaction(mirror, || initialize_tasks(mirror, category))?;
+    }
+
+    // the cursor, and any tally that moved on without a program changing
+    store_state(mirror)
 }
 
-pub fn run() -> Result<(), Box<dyn Error>> {
+pub fn run(
+    transport: &dyn MediaWikiTransport,
+    site: &SiteProfile,
+    options: &SyncOptions,
+    report: &Reporter,
+) -> Result<(), Box<dyn Error>> {
     // first gather up all languages by name
-    let category_name = "Programming_Languages";
-    let languages: Languages = query(make_category_query_args(category_name))?;
+    let languages: Languages = query(transport, make_category_query_args(&site.language_category))?;
     let lan = &languages::Langs::new(&languages)?;
 
-    // get previous timestamp (if it exists).
-    let timestamp = read_revision_timestamp().ok();
-    let revisions: Revisions = query(make_recentchanges_query_args(timestamp))?;
-    let mut rc = revisions.recentchanges;
-    rc.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let repo = Repo::new(".");
+    let mirror = Mirror {
+        transport,
+        site,
+        lan,
+        options,
+        report,
+        repo: &repo,
+        state: Mutex::new(read_state(site)?),
+    };
+    let result = sync(&mirror);
+    report.unknown_languages(lan.unknown_languages());
+    result
+}
 
-    let categories = [
-        "Programming_Tasks",
-        "Draft_Programming_Tasks",
-        "Simple",
-    ];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-    // do updates first so that timestamp gets set before reading tasks for new category.
-    
-    let new_categories = categories
-                             .iter()
-                             .map(|category|
-                                  update_tasks(lan, category, &rc))
-                             .collect::<Result<Vec<_>,_>>()?;
-
-    let _unused = new_categories
-                             .iter()
-                             .map(|category| {
-                                  match category {
-                                      Some(cat) => initialize_tasks(lan, cat),
-                                      _ => Ok(()),
-                                  }
-                             })
-                             .collect::<Result<Vec<_>,_>>()?;
+    #[test]
+    fn parse_response_turns_error_into_api_error() {
+        let args = vec![("action".to_owned(), "query".to_owned())];
+        let response =
+            json!({"error": {"code": "maxlag", "info": "Waiting for a database server"}});
+        let error = parse_response(&response.to_string(), &args).unwrap_err();
+        match error.downcast_ref::<RosettaError>() {
+            Some(RosettaError::ApiError {
+                code,
+                info,
+                args: error_args,
+            }) => {
+                assert_eq!(code, "maxlag");
+                assert_eq!(info, "Waiting for a database server");
+                assert_eq!(error_args, &args);
+            }
+            other => panic!("expected an API error, got {:?}", other),
+        }
+    }
 
-    Ok(())
+    #[test]
+    fn pages_concat_merges_revisions_split_across_parts() {
+        let revision = |revid: u64| {
+            json!({"content": revid.to_string(), "revid": revid,
+                "timestamp": "2021-01-01T00:00:00Z", "user": "alice", "comment": ""})
+        };
+        // the second part, asked for with rvcontinue, carries on with the
+        // same page and then starts another
+        let mut pages: Pages = serde_json::from_value(json!({"pages": [
+            {"pageid": 10, "title": "Hello world", "revisions": [revision(101), revision(102)]},
+        ]}))
+        .unwrap();
+        pages.concat(
+            serde_json::from_value(json!({"pages": [
+                {"pageid": 10, "title": "Hello world", "revisions": [revision(103)]},
+                {"pageid": 11, "title": "Fizz buzz", "revisions": [revision(110)]},
+            ]}))
+            .unwrap(),
+        );
+
+        let revids = |page: &PageDetail| page.revisions.iter().map(|r| r.revid).collect::<Vec<_>>();
+        assert_eq!(pages.pages.len(), 2);
+        assert_eq!(revids(&pages.pages[0]), [101, 102, 103]);
+        assert_eq!(revids(&pages.pages[1]), [110]);
+    }
 }
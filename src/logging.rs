@@ -0,0 +1,102 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use serde_json::Map;
+use std::error::Error;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+// Writes log records to stderr, one per line, so that they stay apart
+// from anything else the mirror or git print.
+struct Logger {
+    format: LogFormat,
+}
+
+// Collects the key-value fields of a record (task, lang, revid, ...).
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+impl Logger {
+    fn format(&self, record: &Record) -> String {
+        let mut fields = Fields(vec![]);
+        let _ = record.key_values().visit(&mut fields);
+
+        match self.format {
+            LogFormat::Text => {
+                let mut line = format!("{:5} {}", record.level(), record.args());
+                for (key, value) in fields.0 {
+                    line += &format!(" {}={:?}", key, value);
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("level".to_owned(), record.level().as_str().into());
+                object.insert("target".to_owned(), record.target().into());
+                object.insert("message".to_owned(), record.args().to_string().into());
+                for (key, value) in fields.0 {
+                    object.insert(key, value.into());
+                }
+                serde_json::Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    // the http client's own chatter is only of interest when it goes wrong
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (metadata.target().starts_with("rosettamirror") || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.format(record);
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+// Info by default; each -v lowers the threshold one level and each -q
+// raises it, down to error messages only.
+pub fn init(format: LogFormat, verbose: u64, quiet: u64) -> Result<(), Box<dyn Error>> {
+    let levels = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+    let index = (2 + verbose as i64 - quiet as i64).clamp(0, levels.len() as i64 - 1);
+    log::set_boxed_logger(Box::new(Logger { format }))?;
+    log::set_max_level(levels[index as usize].to_level_filter());
+    Ok(())
+}
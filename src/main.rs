@@ -1,26 +1,178 @@
-use rosettamirror;
+use rosettamirror::logging::{self, LogFormat};
+use rosettamirror::report::Reporter;
+use rosettamirror::site::SiteProfile;
+use rosettamirror::transport::{
+    request_interval, FixtureTransport, RateLimitedTransport, RecordingTransport, ReqwestTransport,
+};
+use rosettamirror::SyncOptions;
 use std::env;
 use std::error::Error;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 
 extern crate structopt;
 
+// --rate as the time between requests
+fn parse_rate(s: &str) -> Result<Duration, String> {
+    let rate: f64 = s.parse().map_err(|_| format!("not a number: {}", s))?;
+    request_interval(rate)
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 struct Opt {
     #[structopt(short = "d", long = "directory")]
     directory: String,
 
+    /// Refetch every task in every category and commit it as a single full resync
     #[structopt(short = "a", long = "all")]
     all: bool,
+
+    /// Compare the mirror's tallies with the wiki without changing anything,
+    /// exiting with an error if any task is stale, missing or extra
+    #[structopt(long = "verify", conflicts_with = "all")]
+    verify: bool,
+
+    /// Mirror new categories with a commit for every revision of every task
+    #[structopt(long = "backfill", conflicts_with_all = &["all", "verify"])]
+    backfill: bool,
+
+    /// Replay API responses from a fixture file instead of querying the wiki
+    #[structopt(long = "replay", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// Save every API response of this run to a fixture file
+    #[structopt(long = "record")]
+    record: Option<PathBuf>,
+
+    /// Number of task batches to download concurrently
+    #[structopt(short = "j", long = "jobs", default_value = "1")]
+    jobs: usize,
+
+    /// Most requests per second sent to the wiki, across all jobs (0 for no limit)
+    #[structopt(long = "rate", default_value = "2", parse(try_from_str = parse_rate))]
+    rate: Duration,
+
+    /// Seconds of database replication lag at which the wiki should turn us away
+    #[structopt(long = "maxlag", default_value = "5")]
+    maxlag: u32,
+
+    /// How often a request failing with a timeout, 5xx or maxlag error is retried
+    #[structopt(long = "retries", default_value = "5")]
+    retries: u32,
+
+    /// Log more detail (may be repeated)
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u64,
+
+    /// Log only warnings and errors, or with -qq only errors
+    #[structopt(short = "q", long = "quiet", parse(from_occurrences))]
+    quiet: u64,
+
+    /// Format of log lines written to stderr: text or json
+    #[structopt(long = "log-format", default_value = "text", possible_values = &["text", "json"])]
+    log_format: LogFormat,
+
+    /// Write a JSON report of what this run changed
+    #[structopt(long = "report")]
+    report: Option<PathBuf>,
+
+    /// JSON site profile describing the wiki to mirror (defaults to rosettacode.org)
+    #[structopt(short = "c", long = "config")]
+    config: Option<PathBuf>,
+
+    /// URL of the wiki's api.php
+    #[structopt(long = "endpoint")]
+    endpoint: Option<String>,
+
+    /// Category listing the languages used in section headers
+    #[structopt(long = "language-category")]
+    language_category: Option<String>,
+
+    /// Category of tasks to mirror (may be repeated)
+    #[structopt(long = "task-category")]
+    task_categories: Vec<String>,
+
+    /// Name of the template that introduces each language section
+    #[structopt(long = "header-template")]
+    header_template: Option<String>,
+}
+
+fn site_profile(opt: &Opt, cwd: &Path) -> Result<SiteProfile, Box<dyn Error>> {
+    let mut site = match &opt.config {
+        Some(config) => SiteProfile::load(&cwd.join(config).to_string_lossy())?,
+        None => SiteProfile::default(),
+    };
+    if let Some(endpoint) = &opt.endpoint {
+        site.endpoint = endpoint.clone();
+    }
+    if let Some(language_category) = &opt.language_category {
+        site.language_category = language_category.clone();
+    }
+    if !opt.task_categories.is_empty() {
+        site.task_categories = opt.task_categories.clone();
+    }
+    if let Some(header_template) = &opt.header_template {
+        site.header_template = header_template.clone();
+    }
+    Ok(site)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let opt = Opt::from_args();
-    fs::DirBuilder::new()
-        .recursive(true)
-        .create(&opt.directory)?;
+    logging::init(opt.log_format, opt.verbose, opt.quiet)?;
+
+    // resolve config and fixture paths before moving into the mirror directory
+    let cwd = env::current_dir()?;
+    let site = site_profile(&opt, &cwd)?;
+    let replay = opt.replay.map(|p| cwd.join(p));
+    let record = opt.record.map(|p| cwd.join(p));
+    let report = opt.report.map(|p| cwd.join(p));
+
+    if !opt.verify {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .create(&opt.directory)?;
+    }
     env::set_current_dir(&opt.directory)?;
-    rosettamirror::run()
+
+    let options = SyncOptions {
+        full_resync: opt.all,
+        verify: opt.verify,
+        backfill: opt.backfill,
+        jobs: opt.jobs,
+    };
+
+    let live = RateLimitedTransport::new(
+        ReqwestTransport::new(&site.endpoint)
+            .with_maxlag(opt.maxlag)
+            .with_retries(opt.retries),
+        opt.rate,
+    );
+
+    let reporter = Reporter::new();
+
+    let result = match (replay, record) {
+        (Some(replay), _) => {
+            let fixtures = FixtureTransport::load(&replay.to_string_lossy())?;
+            rosettamirror::run(&fixtures, &site, &options, &reporter)
+        }
+        (None, Some(record)) => {
+            let recorder = RecordingTransport::new(live);
+            let result = rosettamirror::run(&recorder, &site, &options, &reporter);
+            recorder.save(&record.to_string_lossy())?;
+            result
+        }
+        (None, None) => rosettamirror::run(&live, &site, &options, &reporter),
+    };
+
+    if let Some(report) = report {
+        if let Err(e) = &result {
+            reporter.failed(e.as_ref());
+        }
+        reporter.save(&report.to_string_lossy())?;
+    }
+    result
 }
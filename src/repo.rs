@@ -0,0 +1,174 @@
+use crate::error::RosettaError;
+use git2::build::CheckoutBuilder;
+use git2::{Commit, DiffOptions, ErrorCode, Index, IndexAddOption, Repository, Signature, Time};
+use std::error::Error;
+use std::sync::Mutex;
+
+// Committer of every commit, so that the mirror doesn't depend on the
+// git configuration of whoever happens to run it.
+const COMMITTER_NAME: &str = "rosettamirror";
+const COMMITTER_EMAIL: &str = "rosettamirror@localhost";
+
+// Who made a change on the wiki, and when (seconds since the epoch).
+pub struct Author {
+    pub name: String,
+    pub email: String,
+    pub time: i64,
+}
+
+// The mirror's git repository, written through libgit2 rather than by
+// running git. It is opened, or created, when first needed. Commits are
+// built from an index kept in memory, seeded from HEAD, so .git/index is
+// never written and can't be left locked; `git reset` brings it up to
+// date for anyone looking at the mirror by hand.
+pub struct Repo {
+    path: String,
+    repo: Mutex<Option<Repository>>,
+}
+
+impl Repo {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            repo: Mutex::new(None),
+        }
+    }
+
+    fn with_repo<T>(
+        &self,
+        f: impl FnOnce(&Repository) -> Result<T, git2::Error>,
+    ) -> Result<T, git2::Error> {
+        let mut repo = self
+            .repo
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if repo.is_none() {
+            let opened = match Repository::open(&self.path) {
+                Ok(opened) => opened,
+                Err(e) if e.code() == ErrorCode::NotFound => Repository::init(&self.path)?,
+                Err(e) => return Err(e),
+            };
+            let mut index = Index::new()?;
+            if let Some(head) = head_commit(&opened)? {
+                index.read_tree(&head.tree()?)?;
+            }
+            opened.set_index(&mut index)?;
+            *repo = Some(opened);
+        }
+        f(repo.as_ref().unwrap())
+    }
+
+    // Paths below `pathspec` that differ from the last commit, after
+    // staging everything there.
+    pub fn staged_paths(&self, pathspec: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let paths = self
+            .with_repo(|repo| {
+                stage(repo, pathspec)?;
+                let index = repo.index()?;
+                let head = head_commit(repo)?.map(|commit| commit.tree()).transpose()?;
+                let mut options = DiffOptions::new();
+                options.pathspec(pathspec);
+                let diff =
+                    repo.diff_tree_to_index(head.as_ref(), Some(&index), Some(&mut options))?;
+                Ok(diff
+                    .deltas()
+                    .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect())
+            })
+            .map_err(RosettaError::git("diff"))?;
+        Ok(paths)
+    }
+
+    // Commit the whole working tree, returning the new commit's hash, or
+    // None if nothing changed since the last commit.
+    pub fn commit(
+        &self,
+        message: &str,
+        author: Option<&Author>,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let message = git2::message_prettify(message, None).map_err(RosettaError::git("commit"))?;
+        let hash = self
+            .with_repo(|repo| {
+                stage(repo, "*")?;
+                let mut index = repo.index()?;
+                let tree_id = index.write_tree()?;
+                let parent = head_commit(repo)?;
+                let unchanged = match &parent {
+                    Some(parent) => parent.tree_id() == tree_id,
+                    None => index.is_empty(),
+                };
+                if unchanged {
+                    return Ok(None);
+                }
+
+                let tree = repo.find_tree(tree_id)?;
+                let (committer, author) = match author {
+                    Some(author) => {
+                        let time = Time::new(author.time, 0);
+                        (
+                            Signature::new(COMMITTER_NAME, COMMITTER_EMAIL, &time)?,
+                            Signature::new(&author.name, &author.email, &time)?,
+                        )
+                    }
+                    None => {
+                        let committer = Signature::now(COMMITTER_NAME, COMMITTER_EMAIL)?;
+                        (committer.clone(), committer)
+                    }
+                };
+                let parents = parent.iter().collect::<Vec<_>>();
+                let oid =
+                    repo.commit(Some("HEAD"), &author, &committer, &message, &tree, &parents)?;
+                Ok(Some(oid.to_string()))
+            })
+            .map_err(RosettaError::git("commit"))?;
+        Ok(hash)
+    }
+
+    // Open the repository, creating it if there isn't one yet.
+    pub fn open(&self) -> Result<(), Box<dyn Error>> {
+        self.with_repo(|_| Ok(()))
+            .map_err(RosettaError::git("open"))?;
+        Ok(())
+    }
+
+    // Throw away every change since the last commit, including files
+    // that were created since. Before the first commit that is every file
+    // in the tree.
+    pub fn rollback(&self) -> Result<(), Box<dyn Error>> {
+        self.with_repo(|repo| {
+            let tree = match head_commit(repo)? {
+                Some(head) => head.tree()?,
+                None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+            };
+            let mut checkout = CheckoutBuilder::new();
+            checkout
+                .force()
+                .remove_untracked(true)
+                .refresh(false)
+                .update_index(false);
+            repo.checkout_tree(tree.as_object(), Some(&mut checkout))?;
+            repo.index()?.read_tree(&tree)
+        })
+        .map_err(RosettaError::git("reset"))?;
+        Ok(())
+    }
+}
+
+// Add new and modified files below `pathspec` to the index and drop
+// those that were removed, like `git add`.
+fn stage(repo: &Repository, pathspec: &str) -> Result<(), git2::Error> {
+    let mut index = repo.index()?;
+    index.add_all([pathspec], IndexAddOption::DEFAULT, None)?;
+    index.update_all([pathspec], None)
+}
+
+fn head_commit(repo: &Repository) -> Result<Option<Commit<'_>>, git2::Error> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?)),
+        Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
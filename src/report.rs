@@ -0,0 +1,171 @@
+use crate::error::RosettaError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskChange {
+    pub category: String,
+    pub title: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskRename {
+    pub category: String,
+    pub from: String,
+    pub to: String,
+}
+
+// a task that moved from one category's tree to another's
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskMove {
+    pub title: String,
+    pub from: String,
+    pub to: String,
+}
+
+impl TaskChange {
+    fn new(category: &str, title: &str) -> Self {
+        Self {
+            category: category.to_owned(),
+            title: title.to_owned(),
+        }
+    }
+}
+
+// What a single run of the mirror did, for consumption by other tools.
+#[derive(Serialize, Debug, Default)]
+pub struct SyncReport {
+    pub tasks_added: Vec<TaskChange>,
+    pub tasks_updated: Vec<TaskChange>,
+    pub tasks_deleted: Vec<TaskChange>,
+    pub tasks_renamed: Vec<TaskRename>,
+    pub tasks_moved: Vec<TaskMove>,
+    // number of program files written, by language directory
+    pub files_written: BTreeMap<String, usize>,
+    pub unknown_languages: BTreeSet<String>,
+    pub failed_fetches: Vec<TaskChange>,
+    // found by verification: behind the wiki, not mirrored, no longer a task
+    pub stale_tasks: Vec<TaskChange>,
+    pub missing_tasks: Vec<TaskChange>,
+    pub extra_tasks: Vec<TaskChange>,
+    pub commits: Vec<String>,
+    // timestamps of the first and last recent change consumed
+    pub changes_from: Option<String>,
+    pub changes_to: Option<String>,
+    pub error: Option<String>,
+}
+
+// Collects the report from all threads of a run.
+#[derive(Default)]
+pub struct Reporter {
+    report: Mutex<SyncReport>,
+}
+
+impl Reporter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut SyncReport)) {
+        f(&mut self
+            .report
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    pub fn task_added(&self, category: &str, title: &str) {
+        self.update(|r| r.tasks_added.push(TaskChange::new(category, title)))
+    }
+
+    pub fn task_updated(&self, category: &str, title: &str) {
+        self.update(|r| r.tasks_updated.push(TaskChange::new(category, title)))
+    }
+
+    pub fn task_deleted(&self, category: &str, title: &str) {
+        self.update(|r| r.tasks_deleted.push(TaskChange::new(category, title)))
+    }
+
+    pub fn task_renamed(&self, category: &str, from: &str, to: &str) {
+        self.update(|r| {
+            r.tasks_renamed.push(TaskRename {
+                category: category.to_owned(),
+                from: from.to_owned(),
+                to: to.to_owned(),
+            })
+        })
+    }
+
+    pub fn task_moved(&self, title: &str, from: &str, to: &str) {
+        self.update(|r| {
+            r.tasks_moved.push(TaskMove {
+                title: title.to_owned(),
+                from: from.to_owned(),
+                to: to.to_owned(),
+            })
+        })
+    }
+
+    pub fn fetch_failed(&self, category: &str, title: &str) {
+        self.update(|r| r.failed_fetches.push(TaskChange::new(category, title)))
+    }
+
+    pub fn task_stale(&self, category: &str, title: &str) {
+        self.update(|r| r.stale_tasks.push(TaskChange::new(category, title)))
+    }
+
+    pub fn task_missing(&self, category: &str, title: &str) {
+        self.update(|r| r.missing_tasks.push(TaskChange::new(category, title)))
+    }
+
+    pub fn task_extra(&self, category: &str, title: &str) {
+        self.update(|r| r.extra_tasks.push(TaskChange::new(category, title)))
+    }
+
+    // program files live in <category>/<task>/<language>/
+    pub fn files_written(&self, paths: &[String]) {
+        self.update(|r| {
+            for path in paths {
+                let lang = Path::new(path)
+                    .parent()
+                    .and_then(|dir| dir.file_name())
+                    .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+                *r.files_written.entry(lang).or_insert(0) += 1;
+            }
+        })
+    }
+
+    pub fn unknown_languages(&self, langs: Vec<String>) {
+        self.update(|r| r.unknown_languages.extend(langs))
+    }
+
+    pub fn commit(&self, hash: &str) {
+        self.update(|r| r.commits.push(hash.to_owned()))
+    }
+
+    pub fn changes_consumed(&self, from: &str, to: &str) {
+        self.update(|r| {
+            r.changes_from = Some(from.to_owned());
+            r.changes_to = Some(to.to_owned());
+        })
+    }
+
+    pub fn failed(&self, error: &dyn Error) {
+        self.update(|r| r.error = Some(error.to_string()))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let f = File::create(path).map_err(RosettaError::file(path))?;
+        let mut b = BufWriter::new(f);
+        let report = self
+            .report
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let s = serde_json::to_string_pretty(&*report)?;
+        b.write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
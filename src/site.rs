@@ -0,0 +1,41 @@
+use crate::error::RosettaError;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+// Everything that ties the mirror to a particular MediaWiki site.
+// The defaults describe rosettacode.org; a config file only needs to
+// name the fields that differ.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SiteProfile {
+    pub endpoint: String,
+    pub language_category: String,
+    pub task_categories: Vec<String>,
+    pub header_template: String,
+}
+
+impl Default for SiteProfile {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://rosettacode.org/mw/api.php".to_owned(),
+            language_category: "Programming_Languages".to_owned(),
+            task_categories: vec![
+                "Programming_Tasks".to_owned(),
+                "Draft_Programming_Tasks".to_owned(),
+                "Simple".to_owned(),
+            ],
+            header_template: "header".to_owned(),
+        }
+    }
+}
+
+impl SiteProfile {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let f = File::open(path).map_err(RosettaError::file(path))?;
+        let mut b = BufReader::new(f);
+        let mut s = String::new();
+        b.read_to_string(&mut s)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+}
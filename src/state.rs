@@ -0,0 +1,168 @@
+use crate::error::RosettaError;
+use crate::write_code_onig;
+use crate::Task;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Everything the mirror knows about what it has mirrored. It is kept
+// inside .git rather than in the tree, so that keeping it up to date
+// never needs a commit of its own.
+pub const STATE_FILE: &str = ".git/rosettamirror.json";
+const STATE_VERSION: u32 = 1;
+
+// A task as it was last written: the revision its programs come from,
+// and where they went. Tallies from before the state file only have the
+// pageid and revid.
+#[derive(Clone, Hash, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct WrittenTask {
+    pub pageid: u64,
+    #[serde(default)]
+    pub title: String,
+    pub revid: u64,
+    #[serde(default)]
+    pub directory: String,
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl WrittenTask {
+    pub fn new(
+        category: &str,
+        pageid: u64,
+        title: &str,
+        revid: u64,
+        files: Vec<String>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            pageid,
+            title: title.to_owned(),
+            revid,
+            directory: write_code_onig::task_directory(category, title)?,
+            files,
+        })
+    }
+}
+
+// The last recent change the mirror has dealt with. Changes are ordered
+// by time and then by rcid, as several can share the same second.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncCursor {
+    pub timestamp: String,
+    pub rcid: u64,
+}
+
+impl SyncCursor {
+    pub fn new(timestamp: &str, rcid: u64) -> Self {
+        Self {
+            timestamp: timestamp.to_owned(),
+            rcid,
+        }
+    }
+
+    pub fn position(&self) -> Result<(i64, u64), Box<dyn Error>> {
+        change_position(&self.timestamp, self.rcid)
+    }
+}
+
+pub fn change_position(timestamp: &str, rcid: u64) -> Result<(i64, u64), Box<dyn Error>> {
+    let time = chrono::DateTime::parse_from_rfc3339(timestamp)?.timestamp();
+    Ok((time, rcid))
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct CategoryState {
+    pub tasks: Vec<WrittenTask>,
+    // tasks that could not be fetched or written, to be tried again
+    #[serde(default)]
+    pub failed: Vec<Task>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MirrorState {
+    pub version: u32,
+    // the version of the mirror that last wrote the state
+    pub tool_version: String,
+    // the last recent change dealt with
+    pub cursor: Option<SyncCursor>,
+    pub categories: BTreeMap<String, CategoryState>,
+}
+
+impl Default for MirrorState {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            cursor: None,
+            categories: BTreeMap::new(),
+        }
+    }
+}
+
+impl MirrorState {
+    // None if there is no state file yet
+    pub fn load() -> Result<Option<Self>, Box<dyn Error>> {
+        if !Path::new(STATE_FILE).exists() {
+            return Ok(None);
+        }
+        let state: Self = read_json_file(STATE_FILE)?;
+        if state.version > STATE_VERSION {
+            return Err(Box::new(RosettaError::UnsupportedState {
+                path: STATE_FILE.to_owned(),
+                version: state.version,
+            }));
+        }
+        Ok(Some(state))
+    }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        self.version = STATE_VERSION;
+        self.tool_version = env!("CARGO_PKG_VERSION").to_owned();
+        let f = File::create(STATE_FILE).map_err(RosettaError::file(STATE_FILE))?;
+        let mut b = BufWriter::new(f);
+        let s = serde_json::to_string_pretty(self)?;
+        b.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn tasks(&self, category: &str) -> Option<HashSet<WrittenTask>> {
+        self.categories
+            .get(category)
+            .map(|state| state.tasks.iter().cloned().collect())
+    }
+
+    // kept in pageid order so that the file diffs well
+    pub fn set_tasks(&mut self, category: &str, tasks: &HashSet<WrittenTask>) {
+        let mut tasks = tasks.iter().cloned().collect::<Vec<_>>();
+        tasks.sort_by_key(|task| task.pageid);
+        self.categories
+            .entry(category.to_owned())
+            .or_default()
+            .tasks = tasks;
+    }
+
+    pub fn failed(&self, category: &str) -> Vec<Task> {
+        self.categories
+            .get(category)
+            .map(|state| state.failed.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_failed(&mut self, category: &str, failed: &[Task]) {
+        self.categories
+            .entry(category.to_owned())
+            .or_default()
+            .failed = failed.to_vec();
+    }
+}
+
+pub fn read_json_file<T: DeserializeOwned>(path: &str) -> Result<T, Box<dyn Error>> {
+    let f = File::open(path).map_err(RosettaError::file(path))?;
+    let mut b = BufReader::new(f);
+    let mut s = String::new();
+    b.read_to_string(&mut s)?;
+    Ok(serde_json::from_str(&s)?)
+}
@@ -0,0 +1,306 @@
+use crate::error::RosettaError;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often a request that failed for a transient reason is repeated
+const DEFAULT_RETRIES: u32 = 5;
+// wait before the first retry, doubled for every retry after that
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub trait MediaWikiTransport: Sync {
+    fn get(&self, args: &[(String, String)]) -> Result<String, Box<dyn Error>>;
+}
+
+// Canonical form of a request, used as the key for recorded responses.
+fn fixture_key(args: &[(String, String)]) -> String {
+    url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(args.iter())
+        .finish()
+}
+
+pub struct ReqwestTransport {
+    endpoint: String,
+    client: reqwest::blocking::Client,
+    maxlag: Option<u32>,
+    retries: u32,
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Timeout { url: String },
+    Status { url: String, status: u16 },
+    MaxLag { url: String },
+    Network { url: String, source: reqwest::Error },
+}
+
+impl TransportError {
+    // whether the same request might succeed if tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TransportError::Timeout { .. } | TransportError::MaxLag { .. } => true,
+            TransportError::Status { status, .. } => *status == 429 || *status >= 500,
+            TransportError::Network { source, .. } => source.is_connect(),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Timeout { url } => write!(f, "Timed out: {}", url),
+            TransportError::Status { url, status } => write!(f, "HTTP {}: {}", status, url),
+            TransportError::MaxLag { url } => write!(f, "Wiki lagged too long: {}", url),
+            TransportError::Network { url, source } => write!(f, "{}: {}", source, url),
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransportError::Network { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl ReqwestTransport {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_owned(),
+            client: reqwest::blocking::Client::new(),
+            maxlag: None,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    // Ask the wiki to refuse requests while its replication lag exceeds
+    // this many seconds, see https://www.mediawiki.org/wiki/Manual:Maxlag_parameter
+    pub fn with_maxlag(mut self, seconds: u32) -> Self {
+        self.maxlag = Some(seconds);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // A single attempt at the request. A failure comes with the delay the
+    // wiki asked for before trying again, if it gave one.
+    fn try_get(&self, url: &str) -> Result<String, (TransportError, Option<Duration>)> {
+        let network = |source: reqwest::Error| {
+            let url = url.to_owned();
+            if source.is_timeout() {
+                (TransportError::Timeout { url }, None)
+            } else {
+                (TransportError::Network { url, source }, None)
+            }
+        };
+
+        let response = self.client.get(url).send().map_err(network)?;
+        let delay = retry_after(&response);
+        let status = response.status();
+        let lagged = response
+            .headers()
+            .get("MediaWiki-API-Error")
+            .is_some_and(|code| code == "maxlag");
+
+        if lagged {
+            let url = url.to_owned();
+            return Err((TransportError::MaxLag { url }, delay));
+        }
+        if !status.is_success() {
+            let url = url.to_owned();
+            let status = status.as_u16();
+            return Err((TransportError::Status { url, status }, delay));
+        }
+        response.text().map_err(network)
+    }
+}
+
+fn retry_after(response: &reqwest::blocking::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+impl MediaWikiTransport for ReqwestTransport {
+    fn get(&self, args: &[(String, String)]) -> Result<String, Box<dyn Error>> {
+        let mut query = url::Url::parse(&self.endpoint)?;
+
+        query.query_pairs_mut().extend_pairs(args.iter());
+        if let Some(maxlag) = self.maxlag {
+            query
+                .query_pairs_mut()
+                .append_pair("maxlag", &maxlag.to_string());
+        }
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut retries = 0;
+        loop {
+            match self.try_get(query.as_str()) {
+                Ok(body) => return Ok(body),
+                Err((error, delay)) if error.is_transient() && retries < self.retries => {
+                    thread::sleep(delay.unwrap_or(backoff));
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    retries += 1;
+                }
+                Err((error, _)) => return Err(Box::new(error)),
+            }
+        }
+    }
+}
+
+// The time between requests that sends no more than the given number per
+// second (zero means no limit). A rate that is negative, or so low that
+// the time doesn't fit in a Duration, is refused.
+pub fn request_interval(requests_per_second: f64) -> Result<Duration, String> {
+    if requests_per_second == 0.0 {
+        return Ok(Duration::ZERO);
+    }
+    if requests_per_second < 0.0 || requests_per_second.is_nan() {
+        return Err("rate must be a number no less than zero".to_owned());
+    }
+    Duration::try_from_secs_f64(1.0 / requests_per_second).map_err(|_| "rate too low".to_owned())
+}
+
+// Spaces requests evenly so that, across all threads, they are sent to
+// the wiki no closer together than the given interval.
+pub struct RateLimitedTransport<T: MediaWikiTransport> {
+    inner: T,
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl<T: MediaWikiTransport> RateLimitedTransport<T> {
+    pub fn new(inner: T, interval: Duration) -> Self {
+        Self {
+            inner,
+            interval,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl<T: MediaWikiTransport> MediaWikiTransport for RateLimitedTransport<T> {
+    fn get(&self, args: &[(String, String)]) -> Result<String, Box<dyn Error>> {
+        let slot = {
+            let mut next_slot = self
+                .next_slot
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+        self.inner.get(args)
+    }
+}
+
+// Replays responses previously captured by a RecordingTransport (or
+// inserted by hand), so a mirror can be run without the live wiki.
+#[derive(Default)]
+pub struct FixtureTransport {
+    responses: BTreeMap<String, String>,
+}
+
+impl FixtureTransport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let f = File::open(path).map_err(RosettaError::file(path))?;
+        let mut b = BufReader::new(f);
+        let mut s = String::new();
+        b.read_to_string(&mut s)?;
+        let responses = serde_json::from_str(&s)?;
+        Ok(Self { responses })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let f = File::create(path).map_err(RosettaError::file(path))?;
+        let mut b = BufWriter::new(f);
+        let s = serde_json::to_string_pretty(&self.responses)?;
+        b.write_all(s.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, args: &[(String, String)], response: &str) {
+        self.responses
+            .insert(fixture_key(args), response.to_owned());
+    }
+}
+
+impl MediaWikiTransport for FixtureTransport {
+    fn get(&self, args: &[(String, String)]) -> Result<String, Box<dyn Error>> {
+        let key = fixture_key(args);
+        match self.responses.get(&key) {
+            Some(response) => Ok(response.clone()),
+            None => Err(Box::new(RosettaError::MissingFixture { query: key })),
+        }
+    }
+}
+
+// Forwards requests to another transport, keeping a copy of every
+// response so that the run can be saved as a fixture file.
+pub struct RecordingTransport<T: MediaWikiTransport> {
+    inner: T,
+    recorded: Mutex<FixtureTransport>,
+}
+
+impl<T: MediaWikiTransport> RecordingTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            recorded: Mutex::new(FixtureTransport::new()),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.recorded
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .save(path)
+    }
+}
+
+impl<T: MediaWikiTransport> MediaWikiTransport for RecordingTransport<T> {
+    fn get(&self, args: &[(String, String)]) -> Result<String, Box<dyn Error>> {
+        let response = self.inner.get(args)?;
+        self.recorded
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(args, &response);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_interval_refuses_rates_it_cannot_keep() {
+        assert_eq!(request_interval(0.0), Ok(Duration::ZERO));
+        assert_eq!(request_interval(2.0), Ok(Duration::from_millis(500)));
+        assert!(request_interval(1e-300).is_err());
+        assert!(request_interval(-1.0).is_err());
+        assert!(request_interval(f64::NAN).is_err());
+    }
+}
@@ -1,11 +1,13 @@
+use crate::error::RosettaError;
 use crate::languages::*;
-use crate::RosettaError;
+use log::{debug, info};
 use maplit::hashmap;
 use onig::Regex;
 use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::path::Path;
 use unicode_categories::*;
 use unicode_normalization::*;
 
@@ -111,20 +113,105 @@ fn task_to_filename(name: &str) -> Result<String, Box<dyn Error>> {
     Ok(s)
 }
 
+// Template names are case insensitive in their first letter and treat
+// spaces and underscores alike.
+fn template_pattern(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            ' ' | '_' => "[ _]".to_owned(),
+            _ if i == 0 && c.is_alphabetic() => {
+                format!("[{}{}]", c.to_uppercase(), c.to_lowercase())
+            }
+            _ if c.is_alphanumeric() => c.to_string(),
+            _ => format!("\\{}", c),
+        })
+        .collect()
+}
+
+// directory holding all the programs of a task
+pub fn task_directory(dir: &str, task_name: &str) -> Result<String, Box<dyn Error>> {
+    Ok(dir.to_owned() + "/" + &task_to_filename(task_name)?)
+}
+
+// every program below a task's directory, which may not exist
+pub fn programs(task_dir: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut programs = vec![];
+    if !Path::new(task_dir).exists() {
+        return Ok(programs);
+    }
+    for lang_entry in fs::read_dir(task_dir).map_err(RosettaError::file(task_dir))? {
+        let lang_entry = lang_entry?;
+        if !lang_entry.file_type()?.is_dir() {
+            continue;
+        }
+        let lang = lang_entry.file_name().to_string_lossy().into_owned();
+        for program in fs::read_dir(lang_entry.path())? {
+            let program = program?.file_name().to_string_lossy().into_owned();
+            programs.push(task_dir.to_owned() + "/" + &lang + "/" + &program);
+        }
+    }
+    programs.sort();
+    Ok(programs)
+}
+
+// The programs of a task, each paired with the path it gets when the
+// task is renamed.
+pub fn renamed_programs(
+    dir: &str,
+    old_name: &str,
+    new_name: &str,
+) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let old_file_name = task_to_filename(old_name)?;
+    let new_file_name = task_to_filename(new_name)?;
+    let old_dir = dir.to_owned() + "/" + &old_file_name;
+    let new_dir = dir.to_owned() + "/" + &new_file_name;
+
+    let mut renames = vec![];
+    for lang_entry in fs::read_dir(&old_dir).map_err(RosettaError::file(&old_dir))? {
+        let lang_entry = lang_entry?;
+        if !lang_entry.file_type()?.is_dir() {
+            continue;
+        }
+        let lang = lang_entry.file_name().to_string_lossy().into_owned();
+        for program in fs::read_dir(lang_entry.path())? {
+            let program = program?.file_name().to_string_lossy().into_owned();
+            let renamed = match program.strip_prefix(&old_file_name.to_lowercase()) {
+                Some(rest) => new_file_name.to_lowercase() + rest,
+                None => program.clone(),
+            };
+            renames.push((
+                old_dir.clone() + "/" + &lang + "/" + &program,
+                new_dir.clone() + "/" + &lang + "/" + &renamed,
+            ));
+        }
+    }
+    Ok(renames)
+}
+
 pub fn write_code(
     lan: &Langs,
+    header_template: &str,
     dir: &str,
     task_name: &str,
     code: &str,
-) -> Result<(), Box<dyn Error>> {
-    println!("TASK: {}", task_name);
+) -> Result<Vec<String>, Box<dyn Error>> {
+    info!(task = task_name; "writing task");
 
-    let header_re =
-        Regex::new(r"(?m)^===*\{\{[Hh]eader\|(.*?)\}\}(.*?)(?:\z|(?=^===*\{\{[Hh]eader))")?;
+    let header = template_pattern(header_template);
+    let header_re = Regex::new(&format!(
+        r"(?m)^===*\{{\{{{0}\|(.*?)\}}\}}(.*?)(?:\z|(?=^===*\{{\{{{0}))",
+        header
+    ))?;
     let program_re = Regex::new(r"(?mi)<lang *(?: [^>]+)?>(.*?)<\/lang *>")?;
 
-    for header_match in header_re.captures_iter(&code) {
-        let lang = header_match.at(1).ok_or(RosettaError::UnexpectedFormat)?;
+    let mut written = vec![];
+    for header_match in header_re.captures_iter(code) {
+        let lang = header_match
+            .at(1)
+            .ok_or_else(|| RosettaError::MissingLangHeader {
+                task: task_name.to_owned(),
+            })?;
 
         let task_file_name = task_to_filename(task_name)?;
         let lang_file_name = lang_to_filename(lan, lang)?;
@@ -134,25 +221,30 @@ pub fn write_code(
 
         fs::DirBuilder::new().recursive(true).create(&program_dir)?;
 
-        let program_matches = header_match.at(2).ok_or(RosettaError::UnexpectedFormat)?;
+        let program_matches = header_match
+            .at(2)
+            .ok_or_else(|| RosettaError::MissingLangBody {
+                task: task_name.to_owned(),
+                lang: lang.to_owned(),
+            })?;
 
         let programs_opt: Option<Vec<_>> = program_re
             .captures_iter(program_matches)
             .map(|pm| pm.at(1))
             .collect();
 
-        let programs: Vec<_> = programs_opt.ok_or(RosettaError::UnexpectedFormat)?;
+        let programs: Vec<_> = programs_opt.ok_or_else(|| RosettaError::MalformedLangBlock {
+            task: task_name.to_owned(),
+            lang: lang.to_owned(),
+        })?;
 
-        let mut index: u32 = 1;
-        for program in programs.iter() {
+        for (index, program) in (1..).zip(programs.iter()) {
             let qualifier = if programs.len() == 1 {
                 "".to_owned()
             } else {
                 "-".to_owned() + &index.to_string()
             };
 
-            index += 1;
-
             let program_name = program_dir.clone()
                 + "/"
                 + &task_file_name.to_lowercase()
@@ -160,7 +252,8 @@ pub fn write_code(
                 + "."
                 + &extension;
 
-            let f = File::create(&program_name)?;
+            debug!(task = task_name, lang = lang_file_name.as_str(); "writing {}", program_name);
+            let f = File::create(&program_name).map_err(RosettaError::file(&program_name))?;
             let mut f = BufWriter::new(f);
 
             // Decided not to remove trailing (or any other) spaces.
@@ -170,7 +263,8 @@ pub fn write_code(
             // let trailing_spaces_re = Regex::new(r"(?m) +$")?;
             // let no_trailing_program = trailing_spaces_re.replace_all(program, "");
             f.write_all(program.as_bytes())?;
+            written.push(program_name);
         }
     }
-    Ok(())
+    Ok(written)
 }
@@ -0,0 +1,467 @@
+// Shared by the integration tests: an in-memory wiki answering the
+// queries the mirror makes, and a mirror directory to sync into.
+#![allow(dead_code)]
+
+use rosettamirror::report::Reporter;
+use rosettamirror::site::SiteProfile;
+use rosettamirror::transport::MediaWikiTransport;
+use rosettamirror::SyncOptions;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const LANGUAGES: [&str; 3] = ["Python", "C", "Rust"];
+
+pub const HELLO: &str = "=={{header|Python}}==\n<lang python>print('hello')</lang>\n";
+pub const FIZZ: &str = "=={{header|Rust}}==\n<lang rust>fn main(){}</lang>\n";
+
+pub const HELLO_PY: &str = "Programming_Tasks/Hello-world/Python/hello-world.py";
+
+// A wiki with a single task, Hello world, created with the given content.
+pub fn hello_wiki(content: &str) -> FakeWiki {
+    let wiki = FakeWiki::new();
+    wiki.create(
+        10,
+        "Hello world",
+        "Programming_Tasks",
+        101,
+        "2021-01-01T00:00:00Z",
+        content,
+    );
+    wiki
+}
+
+pub struct Revision {
+    pub revid: u64,
+    pub user: String,
+    pub comment: String,
+    pub timestamp: String,
+    pub content: String,
+}
+
+pub struct Page {
+    pub title: String,
+    pub categories: Vec<String>,
+    pub revisions: Vec<Revision>,
+    pub deleted: bool,
+}
+
+#[derive(Default)]
+struct Wiki {
+    pages: BTreeMap<u64, Page>,
+    recentchanges: Vec<Value>,
+    next_rcid: u64,
+    // requests for these pages fail, as if the wiki were down
+    failing: Vec<u64>,
+    // only fetching the content of these pages fails
+    failing_content: Vec<u64>,
+}
+
+// A wiki held in memory. Every change to it is also listed in recent
+// changes, as MediaWiki does.
+#[derive(Default)]
+pub struct FakeWiki {
+    wiki: Mutex<Wiki>,
+}
+
+impl FakeWiki {
+    pub fn new() -> Self {
+        let wiki = FakeWiki::default();
+        wiki.state().next_rcid = 1000;
+        wiki
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, Wiki> {
+        self.wiki
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn push_change(wiki: &mut Wiki, mut change: Value) {
+        wiki.next_rcid += 1;
+        change["rcid"] = json!(wiki.next_rcid);
+        change["ns"] = json!(0);
+        wiki.recentchanges.push(change);
+    }
+
+    // a change listed as it is, rcid and all
+    pub fn raw_change(&self, change: Value) {
+        self.state().recentchanges.push(change);
+    }
+
+    pub fn create(
+        &self,
+        pageid: u64,
+        title: &str,
+        category: &str,
+        revid: u64,
+        timestamp: &str,
+        content: &str,
+    ) {
+        let mut wiki = self.state();
+        wiki.pages.insert(
+            pageid,
+            Page {
+                title: title.to_owned(),
+                categories: vec![category.to_owned()],
+                revisions: vec![Revision {
+                    revid,
+                    user: "alice".to_owned(),
+                    comment: "create".to_owned(),
+                    timestamp: timestamp.to_owned(),
+                    content: content.to_owned(),
+                }],
+                deleted: false,
+            },
+        );
+        Self::push_change(
+            &mut wiki,
+            json!({"type": "new", "title": title, "pageid": pageid, "revid": revid,
+                "old_revid": 0, "timestamp": timestamp, "user": "alice", "comment": "create"}),
+        );
+    }
+
+    pub fn edit(&self, pageid: u64, revid: u64, timestamp: &str, content: &str) {
+        let mut wiki = self.state();
+        let page = wiki.pages.get_mut(&pageid).unwrap();
+        let old_revid = page.revisions.last().unwrap().revid;
+        let title = page.title.clone();
+        page.revisions.push(Revision {
+            revid,
+            user: "bob".to_owned(),
+            comment: "edit".to_owned(),
+            timestamp: timestamp.to_owned(),
+            content: content.to_owned(),
+        });
+        Self::push_change(
+            &mut wiki,
+            json!({"type": "edit", "title": title, "pageid": pageid, "revid": revid,
+                "old_revid": old_revid, "timestamp": timestamp, "user": "bob", "comment": "edit"}),
+        );
+    }
+
+    // Like MediaWiki, the delete log event carries no pageid.
+    pub fn delete(&self, pageid: u64, timestamp: &str) {
+        let mut wiki = self.state();
+        let page = wiki.pages.get_mut(&pageid).unwrap();
+        page.deleted = true;
+        let title = page.title.clone();
+        Self::push_change(
+            &mut wiki,
+            json!({"type": "log", "title": title, "pageid": 0, "revid": 0, "old_revid": 0,
+                "timestamp": timestamp, "user": "carol", "comment": "spam",
+                "logtype": "delete", "logaction": "delete", "logparams": {}}),
+        );
+    }
+
+    // A move adds a null revision to the page, with the content unchanged.
+    pub fn rename(&self, pageid: u64, target: &str, revid: u64, timestamp: &str) {
+        let mut wiki = self.state();
+        let page = wiki.pages.get_mut(&pageid).unwrap();
+        let title = page.title.clone();
+        let content = page.revisions.last().unwrap().content.clone();
+        page.title = target.to_owned();
+        page.revisions.push(Revision {
+            revid,
+            user: "dave".to_owned(),
+            comment: "move".to_owned(),
+            timestamp: timestamp.to_owned(),
+            content,
+        });
+        Self::push_change(
+            &mut wiki,
+            json!({"type": "log", "title": title, "pageid": pageid, "revid": revid,
+                "old_revid": 0, "timestamp": timestamp, "user": "dave", "comment": "move",
+                "logtype": "move", "logaction": "move", "logparams": {"target_title": target}}),
+        );
+    }
+
+    // Recategorizing a page is an edit too, but here only the category
+    // changes, as the revision content isn't looked at.
+    pub fn recategorize(&self, pageid: u64, category: &str) {
+        let mut wiki = self.state();
+        wiki.pages.get_mut(&pageid).unwrap().categories = vec![category.to_owned()];
+    }
+
+    // as the wiki does once changes are older than it keeps them for
+    pub fn forget_changes_before(&self, timestamp: &str) {
+        self.state()
+            .recentchanges
+            .retain(|change| change["timestamp"].as_str().unwrap_or_default() >= timestamp);
+    }
+
+    pub fn fail(&self, pageid: u64) {
+        self.state().failing.push(pageid);
+    }
+
+    pub fn fail_content(&self, pageid: u64) {
+        self.state().failing_content.push(pageid);
+    }
+
+    pub fn recover(&self) {
+        let mut wiki = self.state();
+        wiki.failing.clear();
+        wiki.failing_content.clear();
+    }
+
+    fn category_members(wiki: &Wiki, category: &str) -> Value {
+        if category == "Programming_Languages" {
+            let members = LANGUAGES
+                .iter()
+                .map(|lang| json!({"pageid": 0, "ns": 14, "title": format!("Category:{}", lang)}))
+                .collect::<Vec<_>>();
+            return json!({"query": {"categorymembers": members}});
+        }
+        let members = wiki
+            .pages
+            .iter()
+            .filter(|(_, page)| !page.deleted && page.categories.iter().any(|c| c == category))
+            .map(|(pageid, page)| json!({"pageid": pageid, "ns": 0, "title": page.title}))
+            .collect::<Vec<_>>();
+        json!({"query": {"categorymembers": members}})
+    }
+
+    fn recent_changes(wiki: &Wiki, q: &HashMap<&str, &str>) -> Value {
+        let key = |change: &Value| {
+            (
+                change["timestamp"].as_str().unwrap_or_default().to_owned(),
+                change["rcid"].as_u64().unwrap_or_default(),
+            )
+        };
+        let mut changes = wiki.recentchanges.clone();
+        changes.sort_by_key(key);
+        if q.get("rcdir") == Some(&"newer") {
+            if let Some(start) = q.get("rcstart") {
+                changes.retain(|change| change["timestamp"].as_str().unwrap_or_default() >= *start);
+            }
+        } else {
+            changes.reverse();
+        }
+        let limit = q.get("rclimit").and_then(|l| l.parse().ok()).unwrap_or(500);
+        changes.truncate(limit);
+        json!({"query": {"recentchanges": changes}})
+    }
+
+    fn pages(wiki: &Wiki, q: &HashMap<&str, &str>) -> Value {
+        let prop = q["prop"];
+        let mut pages = vec![];
+        for pageid in q["pageids"]
+            .split('|')
+            .filter_map(|id| id.parse::<u64>().ok())
+        {
+            let page = match wiki.pages.get(&pageid) {
+                Some(page) if !page.deleted => page,
+                _ => {
+                    pages.push(json!({"pageid": pageid, "missing": true}));
+                    continue;
+                }
+            };
+            let mut detail = json!({"pageid": pageid, "ns": 0, "title": page.title});
+            match prop {
+                "info" => detail["lastrevid"] = json!(page.revisions.last().unwrap().revid),
+                "categories" => {
+                    let wanted = q["clcategories"];
+                    let categories = page
+                        .categories
+                        .iter()
+                        .map(|c| format!("Category:{}", c))
+                        .filter(|c| c == wanted)
+                        .map(|c| json!({"ns": 14, "title": c}))
+                        .collect::<Vec<_>>();
+                    if !categories.is_empty() {
+                        detail["categories"] = json!(categories);
+                    }
+                }
+                _ => {
+                    let revisions = if let Some(start) = q.get("rvstartid") {
+                        let start: u64 = start.parse().unwrap();
+                        page.revisions
+                            .iter()
+                            .rev()
+                            .find(|rev| rev.revid <= start)
+                            .into_iter()
+                            .collect::<Vec<_>>()
+                    } else if q.get("rvdir") == Some(&"newer") {
+                        page.revisions.iter().collect()
+                    } else {
+                        page.revisions.last().into_iter().collect()
+                    };
+                    detail["revisions"] = revisions
+                        .into_iter()
+                        .map(|rev| {
+                            json!({"revid": rev.revid, "user": rev.user, "comment": rev.comment,
+                                "timestamp": rev.timestamp, "content": rev.content})
+                        })
+                        .collect();
+                }
+            }
+            pages.push(detail);
+        }
+        json!({"query": {"pages": pages}})
+    }
+}
+
+impl MediaWikiTransport for FakeWiki {
+    fn get(&self, args: &[(String, String)]) -> Result<String, Box<dyn Error>> {
+        let q = args
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<HashMap<_, _>>();
+        let wiki = self.state();
+        if let Some(pageids) = q.get("pageids") {
+            let fetches_content = q.get("prop") == Some(&"revisions");
+            let failing = |f: &u64| pageids.split('|').any(|id| f.to_string() == id);
+            if wiki.failing.iter().any(failing)
+                || (fetches_content && wiki.failing_content.iter().any(failing))
+            {
+                return Err("wiki unavailable".into());
+            }
+        }
+        let mut response = if q.get("list") == Some(&"categorymembers") {
+            let category = q["cmtitle"].trim_start_matches("Category:");
+            Self::category_members(&wiki, category)
+        } else if q.get("list") == Some(&"recentchanges") {
+            Self::recent_changes(&wiki, &q)
+        } else if q.contains_key("prop") {
+            Self::pages(&wiki, &q)
+        } else {
+            return Err(format!("unsupported query {:?}", args).into());
+        };
+        response["batchcomplete"] = json!(true);
+        Ok(response.to_string())
+    }
+}
+
+pub fn site() -> SiteProfile {
+    SiteProfile {
+        endpoint: "http://wiki.test/api.php".to_owned(),
+        task_categories: vec![
+            "Programming_Tasks".to_owned(),
+            "Draft_Programming_Tasks".to_owned(),
+        ],
+        ..SiteProfile::default()
+    }
+}
+
+// The mirror works in the current directory, which all tests share.
+static CWD: Mutex<()> = Mutex::new(());
+
+pub struct TestMirror {
+    pub dir: PathBuf,
+}
+
+impl TestMirror {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join("rosettamirror-tests").join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        Self { dir }
+    }
+
+    pub fn sync(&self, transport: &dyn MediaWikiTransport) -> Result<Value, Box<dyn Error>> {
+        self.sync_with(transport, &SyncOptions::default())
+    }
+
+    // Run the mirror, returning its report.
+    pub fn sync_with(
+        &self,
+        transport: &dyn MediaWikiTransport,
+        options: &SyncOptions,
+    ) -> Result<Value, Box<dyn Error>> {
+        let _cwd = CWD.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        env::set_current_dir(&self.dir)?;
+        let reporter = Reporter::new();
+        rosettamirror::run(transport, &site(), options, &reporter)?;
+        let report = self.dir.join(".git/report.json");
+        reporter.save(&report.to_string_lossy())?;
+        Ok(serde_json::from_str(&fs::read_to_string(report)?)?)
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        Path::new(&self.path(path)).exists()
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    pub fn state(&self) -> Value {
+        serde_json::from_str(&self.read(".git/rosettamirror.json")).unwrap()
+    }
+
+    // the tallied task with this pageid
+    pub fn task(&self, category: &str, pageid: u64) -> Option<Value> {
+        self.state()["categories"][category]["tasks"]
+            .as_array()?
+            .iter()
+            .find(|task| task["pageid"] == pageid)
+            .cloned()
+    }
+
+    // summaries of all commits, newest first
+    pub fn commits(&self) -> Vec<String> {
+        let repo = git2::Repository::open(&self.dir).unwrap();
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_head().unwrap();
+        walk.map(|oid| {
+            let commit = repo.find_commit(oid.unwrap()).unwrap();
+            commit.summary().unwrap_or_default().to_owned()
+        })
+        .collect()
+    }
+
+    pub fn write(&self, path: &str, contents: &str) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    // commit everything in the mirror, as an older version of it would
+    pub fn commit_all(&self, message: &str) {
+        let repo = git2::Repository::open(&self.dir)
+            .or_else(|_| git2::Repository::init(&self.dir))
+            .unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.update_all(["*"], None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@localhost").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap();
+    }
+
+    // whether the working tree matches the last commit; the mirror leaves
+    // .git/index alone, so this doesn't go by it
+    pub fn is_clean(&self) -> bool {
+        let repo = git2::Repository::open(&self.dir).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let mut options = git2::DiffOptions::new();
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        let diff = repo
+            .diff_tree_to_workdir(Some(&tree), Some(&mut options))
+            .unwrap();
+        diff.deltas().len() == 0
+    }
+}
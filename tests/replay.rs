@@ -0,0 +1,72 @@
+mod common;
+
+use common::{hello_wiki, TestMirror, HELLO, HELLO_PY};
+use rosettamirror::transport::{FixtureTransport, RecordingTransport};
+
+// A first sync of a small wiki, written by hand in the format --record
+// saves.
+const INITIAL_SYNC: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/initial_sync.json"
+);
+
+#[test]
+fn replays_recorded_initial_sync() {
+    let fixtures = FixtureTransport::load(INITIAL_SYNC).unwrap();
+    let mirror = TestMirror::new("replay-initial-sync");
+    let report = mirror.sync(&fixtures).unwrap();
+
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    assert_eq!(
+        mirror.read("Programming_Tasks/Fizz-buzz/Rust/fizz-buzz.rust"),
+        "fn main(){}"
+    );
+    assert!(mirror.exists("Draft_Programming_Tasks/Draft-thing/Python/draft-thing.py"));
+
+    let hello = mirror.task("Programming_Tasks", 10).unwrap();
+    assert_eq!(hello["revid"], 101);
+    assert_eq!(hello["title"], "Hello world");
+    assert_eq!(hello["directory"], "Programming_Tasks/Hello-world");
+    assert_eq!(hello["files"], serde_json::json!([HELLO_PY]));
+    assert_eq!(
+        mirror.task("Draft_Programming_Tasks", 12).unwrap()["revid"],
+        120
+    );
+
+    assert_eq!(report["tasks_added"].as_array().unwrap().len(), 3);
+    assert_eq!(
+        mirror.commits(),
+        [
+            "Draft_Programming_Tasks: initial commit",
+            "Programming_Tasks: initial commit"
+        ]
+    );
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn replays_what_was_recorded() {
+    let recorder = RecordingTransport::new(hello_wiki(HELLO));
+    let recorded = TestMirror::new("replay-recorded");
+    let report = recorded.sync(&recorder).unwrap();
+    let fixture = recorded.path(".git/fixture.json");
+    recorder.save(&fixture.to_string_lossy()).unwrap();
+
+    let fixtures = FixtureTransport::load(&fixture.to_string_lossy()).unwrap();
+    let replayed = TestMirror::new("replay-replayed");
+    let replay_report = replayed.sync(&fixtures).unwrap();
+    assert_eq!(replayed.read(HELLO_PY), "print('hello')");
+    assert_eq!(replay_report["tasks_added"], report["tasks_added"]);
+    assert_eq!(
+        replayed.state()["categories"],
+        recorded.state()["categories"]
+    );
+    assert_eq!(replayed.commits(), recorded.commits());
+}
+
+#[test]
+fn replay_fails_on_unrecorded_query() {
+    let mirror = TestMirror::new("replay-unrecorded");
+    let error = mirror.sync(&FixtureTransport::new()).unwrap_err();
+    assert!(error.to_string().starts_with("No recorded response for:"));
+}
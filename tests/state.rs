@@ -0,0 +1,142 @@
+mod common;
+
+use common::{hello_wiki, FakeWiki, TestMirror, FIZZ, HELLO, HELLO_PY};
+use rosettamirror::SyncOptions;
+use serde_json::json;
+
+fn wiki() -> FakeWiki {
+    let wiki = hello_wiki(HELLO);
+    wiki.create(
+        11,
+        "Fizz buzz",
+        "Programming_Tasks",
+        110,
+        "2021-01-02T00:00:00Z",
+        FIZZ,
+    );
+    wiki
+}
+
+// A mirror as it was before the state file: tallies and failed tasks in the
+// category directories and the committed timestamp at the root.
+fn legacy_mirror(name: &str) -> TestMirror {
+    let mirror = TestMirror::new(name);
+    mirror.write(HELLO_PY, "print('hello')");
+    mirror.write("Programming_Tasks/tasks", r#"[{"pageid":10,"revid":101}]"#);
+    mirror.write(
+        "Programming_Tasks/failed",
+        r#"[{"pageid":11,"title":"Fizz buzz"}]"#,
+    );
+    mirror.write("Draft_Programming_Tasks/tasks", "[]");
+    mirror.write("revision_timestamp", r#""2021-01-01T00:00:00Z""#);
+    mirror.commit_all("Programming_Tasks: initial commit");
+    mirror
+}
+
+fn assert_migrated(mirror: &TestMirror) {
+    for legacy in [
+        "Programming_Tasks/tasks",
+        "Programming_Tasks/failed",
+        "Draft_Programming_Tasks/tasks",
+        "revision_timestamp",
+    ] {
+        assert!(!mirror.exists(legacy), "{} still there", legacy);
+    }
+    assert!(mirror
+        .commits()
+        .contains(&"moved mirror state out of the tree".to_owned()));
+
+    let state = mirror.state();
+    assert_eq!(state["version"], 1);
+    assert_eq!(state["tool_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        mirror.task("Programming_Tasks", 10).unwrap(),
+        json!({
+            "pageid": 10,
+            "title": "Hello world",
+            "revid": 101,
+            "directory": "Programming_Tasks/Hello-world",
+            "files": [HELLO_PY],
+        })
+    );
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn migrates_legacy_state() {
+    let wiki = wiki();
+    let mirror = legacy_mirror("state-migrate");
+    mirror.sync(&wiki).unwrap();
+
+    assert_migrated(&mirror);
+    let state = mirror.state();
+    // carried on from revision_timestamp to the last change
+    assert_eq!(
+        state["cursor"],
+        json!({"timestamp": "2021-01-02T00:00:00Z", "rcid": 1002})
+    );
+    // the failed task was carried over, and retried
+    assert_eq!(
+        state["categories"]["Programming_Tasks"]["failed"],
+        json!([])
+    );
+    assert_eq!(mirror.task("Programming_Tasks", 11).unwrap()["revid"], 110);
+    assert!(mirror.exists("Programming_Tasks/Fizz-buzz/Rust/fizz-buzz.rust"));
+}
+
+#[test]
+fn migrates_legacy_state_on_full_resync() {
+    let wiki = wiki();
+    let mirror = legacy_mirror("state-migrate-resync");
+    let options = SyncOptions {
+        full_resync: true,
+        ..SyncOptions::default()
+    };
+    mirror.sync_with(&wiki, &options).unwrap();
+
+    assert_migrated(&mirror);
+    assert_eq!(
+        mirror.state()["cursor"],
+        json!({"timestamp": "2021-01-02T00:00:00Z", "rcid": 1002})
+    );
+}
+
+#[test]
+fn verify_reads_legacy_state_without_migrating() {
+    let wiki = wiki();
+    let mirror = legacy_mirror("state-verify-legacy");
+    let options = SyncOptions {
+        verify: true,
+        ..SyncOptions::default()
+    };
+    // Fizz buzz only failed, so it is missing from the tally
+    let error = mirror.sync_with(&wiki, &options).unwrap_err();
+    assert!(error.to_string().contains("0 stale, 1 missing, 0 extra"));
+    assert!(!mirror.exists(".git/rosettamirror.json"));
+    assert!(mirror.exists("Programming_Tasks/tasks"));
+}
+
+#[test]
+fn verify_leaves_directory_without_git_alone() {
+    let wiki = wiki();
+    let mirror = TestMirror::new("state-verify-no-git");
+    let options = SyncOptions {
+        verify: true,
+        ..SyncOptions::default()
+    };
+    let error = mirror.sync_with(&wiki, &options).unwrap_err();
+    assert!(error.to_string().contains("0 stale, 2 missing, 0 extra"));
+    assert!(!mirror.exists(".git"));
+}
+
+#[test]
+fn refuses_newer_state() {
+    let wiki = wiki();
+    let mirror = TestMirror::new("state-newer");
+    mirror.write(
+        ".git/rosettamirror.json",
+        r#"{"version":99,"tool_version":"9.0.0","cursor":null,"categories":{}}"#,
+    );
+    let error = mirror.sync(&wiki).unwrap_err();
+    assert!(error.to_string().contains("state version 99"));
+}
@@ -0,0 +1,460 @@
+mod common;
+
+use common::{hello_wiki, FakeWiki, TestMirror, FIZZ, HELLO, HELLO_PY};
+use rosettamirror::SyncOptions;
+use serde_json::json;
+
+const HELLO_PROSE: &str = "Say hello.\n=={{header|Python}}==\n<lang python>print('hello')</lang>\n";
+const HELLO_AGAIN: &str =
+    "Say hello.\n=={{header|Python}}==\n<lang python>print('hello again')</lang>\n";
+const HELLO_WITH_C: &str = "=={{header|Python}}==\n<lang python>print('hello')</lang>\n\
+     =={{header|C}}==\n<lang c>int main(){}</lang>\n";
+
+// A wiki with one task, and a mirror that has already synced it.
+fn synced(name: &str) -> (FakeWiki, TestMirror) {
+    let wiki = hello_wiki(HELLO);
+    let mirror = TestMirror::new(name);
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    (wiki, mirror)
+}
+
+#[test]
+fn follows_prose_edit_with_code_edit() {
+    let (wiki, mirror) = synced("sync-prose-edit");
+
+    wiki.edit(10, 102, "2021-02-01T00:00:00Z", HELLO_PROSE);
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.task("Programming_Tasks", 10).unwrap()["revid"], 102);
+    // no program changed, so there is nothing to commit
+    assert_eq!(mirror.commits().len(), 1);
+    assert!(mirror.is_clean());
+
+    wiki.edit(10, 103, "2021-02-02T00:00:00Z", HELLO_AGAIN);
+    let report = mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello again')");
+    assert_eq!(mirror.task("Programming_Tasks", 10).unwrap()["revid"], 103);
+    assert_eq!(report["tasks_updated"].as_array().unwrap().len(), 1);
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn retries_edit_that_failed_to_fetch() {
+    let (wiki, mirror) = synced("sync-failed-edit");
+
+    wiki.edit(10, 102, "2021-02-01T00:00:00Z", HELLO_AGAIN);
+    wiki.fail_content(10);
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    assert_eq!(
+        mirror.state()["categories"]["Programming_Tasks"]["failed"][0]["pageid"],
+        10
+    );
+
+    wiki.recover();
+    let report = mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello again')");
+    assert_eq!(report["tasks_updated"][0]["title"], "Hello world");
+    assert_eq!(report["tasks_added"], json!([]));
+    assert_eq!(mirror.task("Programming_Tasks", 10).unwrap()["revid"], 102);
+    assert_eq!(
+        mirror.state()["categories"]["Programming_Tasks"]["failed"],
+        json!([])
+    );
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn finds_new_task_after_failed_category_query() {
+    let (wiki, mirror) = synced("sync-new-task");
+
+    wiki.create(
+        11,
+        "Fizz buzz",
+        "Programming_Tasks",
+        110,
+        "2021-02-01T00:00:00Z",
+        FIZZ,
+    );
+    wiki.fail(11);
+    assert!(mirror.sync(&wiki).is_err());
+    assert_eq!(
+        mirror.state()["cursor"]["timestamp"],
+        "2021-01-01T00:00:00Z"
+    );
+
+    wiki.recover();
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(
+        mirror.read("Programming_Tasks/Fizz-buzz/Rust/fizz-buzz.rust"),
+        "fn main(){}"
+    );
+    assert_eq!(mirror.task("Programming_Tasks", 11).unwrap()["revid"], 110);
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn orders_changes_made_in_the_same_second() {
+    let (wiki, mirror) = synced("sync-same-second");
+
+    wiki.edit(10, 102, "2021-02-01T00:00:00Z", HELLO_PROSE);
+    wiki.edit(10, 103, "2021-02-01T00:00:00Z", HELLO_AGAIN);
+    // listed again, as a change can be on more than one page of results
+    wiki.raw_change(json!({"type": "edit", "title": "Hello world", "pageid": 10,
+        "revid": 102, "old_revid": 101, "rcid": 1002, "ns": 0,
+        "timestamp": "2021-02-01T00:00:00Z", "user": "bob", "comment": "edit"}));
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello again')");
+    assert_eq!(mirror.task("Programming_Tasks", 10).unwrap()["revid"], 103);
+    assert_eq!(mirror.state()["cursor"]["rcid"], 1003);
+
+    // made in the same second as the cursor, but after it
+    wiki.edit(10, 104, "2021-02-01T00:00:00Z", HELLO);
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    assert_eq!(mirror.task("Programming_Tasks", 10).unwrap()["revid"], 104);
+    assert_eq!(mirror.state()["cursor"]["rcid"], 1004);
+    // one commit for each edit that changed a program, and none for the
+    // duplicate
+    let commits = mirror.commits();
+    assert_eq!(commits.len(), 3);
+    assert!(commits[..2]
+        .iter()
+        .all(|commit| commit.starts_with("task: Hello world")));
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn removes_deleted_task() {
+    let (wiki, mirror) = synced("sync-delete");
+
+    wiki.delete(10, "2021-02-01T00:00:00Z");
+    let report = mirror.sync(&wiki).unwrap();
+    assert!(!mirror.exists("Programming_Tasks/Hello-world"));
+    assert_eq!(mirror.task("Programming_Tasks", 10), None);
+    assert_eq!(report["tasks_deleted"].as_array().unwrap().len(), 1);
+    assert!(mirror.is_clean());
+
+    let options = SyncOptions {
+        verify: true,
+        ..SyncOptions::default()
+    };
+    mirror.sync_with(&wiki, &options).unwrap();
+}
+
+#[test]
+fn keeps_task_when_page_with_same_directory_is_deleted() {
+    let (wiki, mirror) = synced("sync-delete-other");
+
+    // a redirect whose title gives the same directory as the task's
+    wiki.create(
+        12,
+        "Hello-world",
+        "Redirects",
+        120,
+        "2021-02-01T00:00:00Z",
+        "#REDIRECT [[Hello world]]",
+    );
+    wiki.delete(12, "2021-02-02T00:00:00Z");
+    let report = mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    assert!(mirror.task("Programming_Tasks", 10).is_some());
+    assert_eq!(report["tasks_deleted"], json!([]));
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn follows_task_rename() {
+    let (wiki, mirror) = synced("sync-rename");
+
+    wiki.rename(10, "Hello there", 102, "2021-02-01T00:00:00Z");
+    let report = mirror.sync(&wiki).unwrap();
+    assert!(!mirror.exists("Programming_Tasks/Hello-world"));
+    assert_eq!(
+        mirror.read("Programming_Tasks/Hello-there/Python/hello-there.py"),
+        "print('hello')"
+    );
+    assert_eq!(
+        mirror.task("Programming_Tasks", 10).unwrap(),
+        json!({
+            "pageid": 10,
+            "title": "Hello there",
+            "revid": 102,
+            "directory": "Programming_Tasks/Hello-there",
+            "files": ["Programming_Tasks/Hello-there/Python/hello-there.py"],
+        })
+    );
+    assert_eq!(report["tasks_renamed"].as_array().unwrap().len(), 1);
+    assert!(mirror.is_clean());
+
+    // the next edit follows on from the move's revision
+    wiki.edit(10, 103, "2021-02-02T00:00:00Z", HELLO_AGAIN);
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(
+        mirror.read("Programming_Tasks/Hello-there/Python/hello-there.py"),
+        "print('hello again')"
+    );
+}
+
+#[test]
+fn follows_rename_of_task_without_programs() {
+    let (wiki, mirror) = synced("sync-rename-empty");
+    wiki.create(
+        11,
+        "Fizz buzz",
+        "Programming_Tasks",
+        110,
+        "2021-02-01T00:00:00Z",
+        "No solutions yet.",
+    );
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(
+        mirror.task("Programming_Tasks", 11).unwrap()["files"],
+        json!([])
+    );
+
+    wiki.rename(11, "FizzBuzz", 111, "2021-02-02T00:00:00Z");
+    mirror.sync(&wiki).unwrap();
+    let task = mirror.task("Programming_Tasks", 11).unwrap();
+    assert_eq!(task["title"], "FizzBuzz");
+    assert_eq!(task["revid"], 111);
+    assert_eq!(task["directory"], "Programming_Tasks/FizzBuzz");
+
+    wiki.edit(11, 112, "2021-02-03T00:00:00Z", FIZZ);
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(
+        mirror.read("Programming_Tasks/FizzBuzz/Rust/fizzbuzz.rust"),
+        "fn main(){}"
+    );
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn promotes_draft_into_existing_directory() {
+    let (wiki, mirror) = synced("sync-promote");
+    wiki.create(
+        12,
+        "Draft thing",
+        "Draft_Programming_Tasks",
+        120,
+        "2021-02-01T00:00:00Z",
+        HELLO,
+    );
+    mirror.sync(&wiki).unwrap();
+    // left behind by an earlier run
+    mirror.write(
+        "Programming_Tasks/Draft-thing/Python/draft-thing.py",
+        "print('old')",
+    );
+    mirror.write("Programming_Tasks/Draft-thing/C/draft-thing.c", "int x;");
+    mirror.commit_all("leftovers");
+
+    wiki.recategorize(12, "Programming_Tasks");
+    let report = mirror.sync(&wiki).unwrap();
+    assert!(!mirror.exists("Draft_Programming_Tasks/Draft-thing"));
+    assert_eq!(
+        mirror.read("Programming_Tasks/Draft-thing/Python/draft-thing.py"),
+        "print('hello')"
+    );
+    assert_eq!(mirror.task("Draft_Programming_Tasks", 12), None);
+    assert_eq!(
+        mirror.task("Programming_Tasks", 12).unwrap()["files"],
+        json!([
+            "Programming_Tasks/Draft-thing/C/draft-thing.c",
+            "Programming_Tasks/Draft-thing/Python/draft-thing.py"
+        ])
+    );
+    assert_eq!(report["tasks_moved"].as_array().unwrap().len(), 1);
+    assert!(mirror.commits()[0].starts_with("moved task: Draft thing"));
+    assert!(mirror.is_clean());
+
+    // the leftover program goes once the task no longer produces it
+    wiki.edit(12, 121, "2021-02-02T00:00:00Z", HELLO_AGAIN);
+    mirror.sync(&wiki).unwrap();
+    assert!(!mirror.exists("Programming_Tasks/Draft-thing/C"));
+    assert_eq!(
+        mirror.read("Programming_Tasks/Draft-thing/Python/draft-thing.py"),
+        "print('hello again')"
+    );
+}
+
+#[test]
+fn refetches_tasks_changed_beyond_recent_changes() {
+    let (wiki, mirror) = synced("sync-refetch");
+
+    wiki.edit(10, 102, "2021-02-01T00:00:00Z", HELLO_AGAIN);
+    wiki.create(
+        11,
+        "Fizz buzz",
+        "Programming_Tasks",
+        110,
+        "2021-02-02T00:00:00Z",
+        FIZZ,
+    );
+    wiki.forget_changes_before("2021-02-02T00:00:00Z");
+    let report = mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello again')");
+    assert!(mirror.exists("Programming_Tasks/Fizz-buzz/Rust/fizz-buzz.rust"));
+    assert_eq!(report["tasks_updated"][0]["title"], "Hello world");
+    assert_eq!(report["tasks_updated"].as_array().unwrap().len(), 1);
+    assert_eq!(report["tasks_added"][0]["title"], "Fizz buzz");
+    assert_eq!(report["tasks_added"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        mirror.state()["cursor"]["timestamp"],
+        "2021-02-02T00:00:00Z"
+    );
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn removes_tasks_deleted_beyond_recent_changes() {
+    let (wiki, mirror) = synced("sync-refetch-deleted");
+
+    wiki.delete(10, "2021-02-01T00:00:00Z");
+    wiki.create(
+        11,
+        "Fizz buzz",
+        "Programming_Tasks",
+        110,
+        "2021-02-02T00:00:00Z",
+        FIZZ,
+    );
+    wiki.forget_changes_before("2021-02-02T00:00:00Z");
+    let report = mirror.sync(&wiki).unwrap();
+    assert!(!mirror.exists("Programming_Tasks/Hello-world"));
+    assert_eq!(mirror.task("Programming_Tasks", 10), None);
+    assert_eq!(report["tasks_deleted"][0]["title"], "Hello world");
+    assert!(mirror.is_clean());
+
+    let options = SyncOptions {
+        verify: true,
+        ..SyncOptions::default()
+    };
+    mirror.sync_with(&wiki, &options).unwrap();
+}
+
+#[test]
+fn full_resync_reports_tasks_already_mirrored_as_updated() {
+    let (wiki, mirror) = synced("sync-full-resync");
+
+    wiki.create(
+        11,
+        "Fizz buzz",
+        "Programming_Tasks",
+        110,
+        "2021-02-01T00:00:00Z",
+        FIZZ,
+    );
+    let options = SyncOptions {
+        full_resync: true,
+        ..SyncOptions::default()
+    };
+    let report = mirror.sync_with(&wiki, &options).unwrap();
+    assert_eq!(report["tasks_updated"][0]["title"], "Hello world");
+    assert_eq!(report["tasks_updated"].as_array().unwrap().len(), 1);
+    assert_eq!(report["tasks_added"][0]["title"], "Fizz buzz");
+    assert_eq!(report["tasks_added"].as_array().unwrap().len(), 1);
+    assert!(mirror.is_clean());
+}
+
+fn backfill() -> SyncOptions {
+    SyncOptions {
+        backfill: true,
+        ..SyncOptions::default()
+    }
+}
+
+#[test]
+fn backfill_counts_final_programs() {
+    let wiki = hello_wiki(HELLO);
+    wiki.edit(10, 102, "2021-01-02T00:00:00Z", HELLO_AGAIN);
+    let mirror = TestMirror::new("sync-backfill");
+    let report = mirror.sync_with(&wiki, &backfill()).unwrap();
+
+    assert_eq!(mirror.read(HELLO_PY), "print('hello again')");
+    assert_eq!(report["files_written"], json!({"Python": 1}));
+    assert_eq!(mirror.commits().len(), 2);
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn backfill_rolls_back_failed_revision() {
+    let wiki = hello_wiki(HELLO);
+    wiki.edit(10, 102, "2021-01-02T00:00:00Z", HELLO_WITH_C);
+    let mirror = TestMirror::new("sync-backfill-failed");
+    // a file where the C program's directory should go
+    mirror.write("Programming_Tasks/Hello-world/C", "in the way");
+    mirror.sync_with(&wiki, &backfill()).unwrap();
+
+    // the first revision is kept, the second left for the next run
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    assert_eq!(
+        mirror.state()["categories"]["Programming_Tasks"]["failed"][0]["pageid"],
+        10
+    );
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn backfill_rolls_back_failed_first_revision() {
+    let wiki = hello_wiki(HELLO_WITH_C);
+    let mirror = TestMirror::new("sync-backfill-failed-first");
+    mirror.write("Programming_Tasks/Hello-world/C", "in the way");
+    mirror.sync_with(&wiki, &backfill()).unwrap();
+
+    // nothing was committed yet, so nothing written before the failure is
+    // left to sweep into the next commit
+    assert!(!mirror.exists(HELLO_PY));
+    assert!(!mirror.exists("Programming_Tasks/Hello-world/C"));
+    assert_eq!(
+        mirror.state()["categories"]["Programming_Tasks"]["failed"][0]["pageid"],
+        10
+    );
+
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(mirror.read(HELLO_PY), "print('hello')");
+    assert_eq!(mirror.commits().len(), 1);
+    assert!(mirror.is_clean());
+}
+
+#[test]
+fn commits_edit_as_wiki_user() {
+    let (wiki, mirror) = synced("sync-author");
+
+    wiki.edit(10, 102, "2021-02-01T00:00:00Z", HELLO_AGAIN);
+    mirror.sync(&wiki).unwrap();
+    let repo = git2::Repository::open(&mirror.dir).unwrap();
+    let commit = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(commit.author().name(), Some("bob"));
+    assert_eq!(commit.author().email(), Some("bob@wiki.test.invalid"));
+    assert_eq!(commit.author().when().seconds(), 1612137600);
+}
+
+#[test]
+fn removes_programs_an_edit_drops() {
+    let (wiki, mirror) = synced("sync-stale-programs");
+
+    wiki.edit(
+        10,
+        102,
+        "2021-02-01T00:00:00Z",
+        "=={{header|Python}}==\n<lang python>print('hello')</lang>\n\
+         =={{header|C}}==\n<lang c>int main(){}</lang>\n",
+    );
+    mirror.sync(&wiki).unwrap();
+    assert_eq!(
+        mirror.task("Programming_Tasks", 10).unwrap()["files"],
+        json!([HELLO_PY, "Programming_Tasks/Hello-world/C/hello-world.c"])
+    );
+
+    wiki.edit(10, 103, "2021-02-02T00:00:00Z", HELLO_AGAIN);
+    mirror.sync(&wiki).unwrap();
+    assert!(!mirror.exists("Programming_Tasks/Hello-world/C"));
+    assert_eq!(mirror.read(HELLO_PY), "print('hello again')");
+    assert_eq!(
+        mirror.task("Programming_Tasks", 10).unwrap()["files"],
+        json!([HELLO_PY])
+    );
+    assert!(mirror.commits()[0].contains("Programming_Tasks/Hello-world/C/hello-world.c"));
+    assert!(mirror.is_clean());
+}